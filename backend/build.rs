@@ -1,7 +1,9 @@
-use tonic_build;
-
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::compile_protos("protos/model_config.proto")?;
     tonic_build::compile_protos("protos/triton.proto")?;
+    // Migrations are embedded in the binary, so rebuild when they change
+    println!("cargo:rerun-if-changed=migrations");
+    println!("cargo:rerun-if-changed=migrations_embeddings");
+    println!("cargo:rerun-if-changed=migrations_sqlite");
     Ok(())
 }
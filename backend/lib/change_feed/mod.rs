@@ -0,0 +1,111 @@
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use futures::{stream, Stream, StreamExt};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_postgres::{AsyncMessage, NoTls};
+
+/// Postgres channel that `upload_items` notifies on
+pub const CHANNEL: &str = "item_changes";
+
+/// NOTIFY payloads are capped at 8000 bytes, so changes are sent in chunks of this many
+const CHANGES_PER_NOTIFY: usize = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Insert,
+    Update,
+}
+
+/// One changed item, as carried in an `item_changes` notification
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ItemChange {
+    pub id: i64,
+    /// HN item type, e.g. `story` or `comment`
+    pub kind: Option<String>,
+    pub op: ChangeOp,
+}
+
+#[derive(Error, Debug)]
+pub enum ChangeFeedError {
+    #[error(transparent)]
+    PostgresError(#[from] tokio_postgres::Error),
+
+    #[error(transparent)]
+    JsonParseError(#[from] serde_json::Error),
+}
+
+/// Sends `changes` on the `item_changes` channel. Inside a transaction, they're delivered on commit
+pub(crate) async fn notify(
+    conn: &mut AsyncPgConnection,
+    changes: &[ItemChange],
+) -> Result<(), diesel::result::Error> {
+    for chunk in changes.chunks(CHANGES_PER_NOTIFY) {
+        let payload = serde_json::to_string(chunk)
+            .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+        sql_query("SELECT pg_notify($1, $2)")
+            .bind::<Text, _>(CHANNEL)
+            .bind::<Text, _>(payload)
+            .execute(conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/**
+Subscribes to item upserts written by the sync workers.
+
+Opens a dedicated connection that `LISTEN`s on `item_changes`; the stream ends when that connection does.
+Notifications sent while no subscriber is connected are not replayed.
+
+The connection is made without TLS, like the connection pool's: a `db_url` with `sslmode=require`
+fails to connect, and `sslmode=prefer` falls back to plain TCP.
+*/
+pub async fn subscribe(
+    db_url: &str,
+) -> Result<impl Stream<Item = Result<ItemChange, ChangeFeedError>>, ChangeFeedError> {
+    let (client, mut connection) = tokio_postgres::connect(db_url, NoTls).await?;
+    let (tx, rx) = flume::unbounded::<Result<Vec<ItemChange>, ChangeFeedError>>();
+
+    // The connection has to be polled for LISTEN to complete, so drive it from its own task
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            let changes = match message {
+                Ok(AsyncMessage::Notification(n)) => {
+                    serde_json::from_str::<Vec<ItemChange>>(n.payload()).map_err(Into::into)
+                }
+                Ok(AsyncMessage::Notice(notice)) => {
+                    debug!("Postgres notice: {}", notice);
+                    continue;
+                }
+                Ok(_) => continue,
+                Err(err) => {
+                    error!("Change feed connection failed: {}", err);
+                    Err(err.into())
+                }
+            };
+            if tx.send(changes).is_err() {
+                debug!("Change feed subscriber dropped, closing connection");
+                break;
+            }
+        }
+    });
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+
+    Ok(rx.into_stream().flat_map(move |changes| {
+        // Keeps the client alive for as long as the stream is
+        let _client = &client;
+        let results: Vec<Result<ItemChange, ChangeFeedError>> = match changes {
+            Ok(changes) => changes.into_iter().map(Ok).collect(),
+            Err(err) => {
+                warn!("Bad change feed message: {}", err);
+                vec![Err(err)]
+            }
+        };
+        stream::iter(results)
+    }))
+}
@@ -1,21 +1,181 @@
 use std::env;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Default re-fetch checkpoints for young stories: 5m, 15m, 1h, 6h, 24h, and a final one at 48h
+const DEFAULT_REFRESH_SCHEDULE: &str = "5m,15m,1h,6h,24h,48h";
+/// Keep below the orchestrator's kill timeout, so pending ids get persisted before SIGKILL
+const DEFAULT_SHUTDOWN_DEADLINE: &str = "20s";
+/// Enough for the HTTP API and a few dozen writers. Ingestion workers only hold one while writing a batch
+const DEFAULT_DB_POOL_SIZE: usize = 32;
+const DEFAULT_DB_POOL_WAIT_TIMEOUT: &str = "30s";
+/// Off by default: partition maintenance runs for as long as it needs
+const DEFAULT_DB_STATEMENT_TIMEOUT: &str = "0s";
+const DEFAULT_DB_CONN_MAX_AGE: &str = "30m";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error(transparent)]
+    VarError(#[from] env::VarError),
+
+    #[error("Invalid value for {0}: {1}")]
+    InvalidValue(String, String),
+}
+
+/// Postgres connection pool settings
+#[derive(Clone, Debug)]
+pub struct DbPoolConfig {
+    /// Max open connections. Optional, set with `DB_POOL_SIZE=32`
+    pub max_size: usize,
+    /// How long a query waits for a free connection before failing. Optional, set with `DB_POOL_WAIT_TIMEOUT="30s"`
+    pub wait_timeout: Duration,
+    /// Server-side limit on each statement. Optional, set with `DB_STATEMENT_TIMEOUT="5m"`, off if `0s`
+    pub statement_timeout: Option<Duration>,
+    /// Connections older than this are closed rather than reused. Optional, set with `DB_CONN_MAX_AGE="30m"`, off if `0s`
+    pub max_age: Option<Duration>,
+}
 
 pub struct Config {
     /// HN API url. required.
     pub hn_api_url: String,
     pub triton_server_addr: String,
     pub db_url: String,
+    /// Story ages at which to re-fetch a story. Optional, set with `REFRESH_SCHEDULE="5m,1h,48h"`
+    pub refresh_schedule: Vec<Duration>,
+    /// How long to drain the realtime queue on shutdown. Optional, set with `SHUTDOWN_DEADLINE="20s"`
+    pub shutdown_deadline: Duration,
+    pub db_pool: DbPoolConfig,
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self, env::VarError> {
+    pub fn from_env() -> Result<Self, ConfigError> {
         let hn_api_url = env::var("HN_API_URL")?;
         let triton_server_addr = env::var("TRITON_SERVER_ADDR")?;
         let db_url = env::var("DB_URL")?;
+        let refresh_schedule = parse_schedule(
+            &env::var("REFRESH_SCHEDULE").unwrap_or(DEFAULT_REFRESH_SCHEDULE.into()),
+        )?;
+        let shutdown_deadline = duration_var("SHUTDOWN_DEADLINE", DEFAULT_SHUTDOWN_DEADLINE)?;
+        let raw_pool_size = env::var("DB_POOL_SIZE").unwrap_or(DEFAULT_DB_POOL_SIZE.to_string());
+        let max_size = raw_pool_size
+            .trim()
+            .parse()
+            .ok()
+            .filter(|size| *size > 0)
+            .ok_or(ConfigError::InvalidValue(
+                "DB_POOL_SIZE".into(),
+                raw_pool_size.clone(),
+            ))?;
+        let db_pool = DbPoolConfig {
+            max_size,
+            wait_timeout: duration_var("DB_POOL_WAIT_TIMEOUT", DEFAULT_DB_POOL_WAIT_TIMEOUT)?,
+            statement_timeout: optional_duration_var(
+                "DB_STATEMENT_TIMEOUT",
+                DEFAULT_DB_STATEMENT_TIMEOUT,
+            )?,
+            max_age: optional_duration_var("DB_CONN_MAX_AGE", DEFAULT_DB_CONN_MAX_AGE)?,
+        };
         Ok(Self {
             hn_api_url,
             triton_server_addr,
             db_url,
+            refresh_schedule,
+            shutdown_deadline,
+            db_pool,
         })
     }
 }
+
+/// A duration like `30s` from the environment variable `name`, or `default` if unset
+fn duration_var(name: &str, default: &str) -> Result<Duration, ConfigError> {
+    let raw = env::var(name).unwrap_or(default.into());
+    parse_duration(raw.trim()).ok_or(ConfigError::InvalidValue(name.into(), raw.clone()))
+}
+
+/// Like `duration_var`, with `0s` meaning none
+fn optional_duration_var(name: &str, default: &str) -> Result<Option<Duration>, ConfigError> {
+    Ok(Some(duration_var(name, default)?).filter(|duration| !duration.is_zero()))
+}
+
+/// Parses a comma-separated list of durations like `30s,5m,1h,2d` into ascending order
+fn parse_schedule(raw: &str) -> Result<Vec<Duration>, ConfigError> {
+    let mut schedule = raw
+        .split(',')
+        .map(|s| parse_duration(s.trim()))
+        .collect::<Option<Vec<_>>>()
+        .ok_or(ConfigError::InvalidValue(
+            "REFRESH_SCHEDULE".into(),
+            raw.into(),
+        ))?;
+    schedule.sort();
+    schedule.dedup();
+    Ok(schedule)
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let unit_idx = s.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = s.split_at(unit_idx);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_secs = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => return None,
+    };
+    // Out of range amounts are rejected rather than wrapped. Callers do second arithmetic in
+    // i64, so that's the limit
+    amount
+        .checked_mul(unit_secs)
+        .filter(|secs| i64::try_from(*secs).is_ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_duration, parse_schedule};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_duration_reads_each_unit() {
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(5 * 60)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(
+            parse_duration("1d"),
+            Some(Duration::from_secs(24 * 60 * 60))
+        );
+        assert_eq!(parse_duration("0s"), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed_input() {
+        for raw in ["", "30", "s", "30x", "-5s", "1.5h", "30 s"] {
+            assert_eq!(parse_duration(raw), None, "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflowing_amounts() {
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / 60)), None);
+        assert_eq!(parse_duration(&format!("{}s", u64::MAX)), None);
+        assert_eq!(parse_duration(&format!("{}s", i64::MAX as u64 + 1)), None);
+        assert_eq!(
+            parse_duration(&format!("{}s", i64::MAX)),
+            Some(Duration::from_secs(i64::MAX as u64))
+        );
+    }
+
+    #[test]
+    fn parse_schedule_sorts_and_dedups() {
+        assert_eq!(
+            parse_schedule("1h, 30m,1h,2d").unwrap(),
+            vec![
+                Duration::from_secs(30 * 60),
+                Duration::from_secs(60 * 60),
+                Duration::from_secs(2 * 24 * 60 * 60),
+            ]
+        );
+        assert!(parse_schedule("1h,soon").is_err());
+    }
+}
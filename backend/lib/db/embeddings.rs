@@ -0,0 +1,135 @@
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Double};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use pgvector::{Vector, VectorExpressionMethods};
+use serde::Serialize;
+
+use super::models::Embedding;
+use super::schema::embeddings;
+
+/// Chunks fetched per story asked for, so stories with many close chunks don't crowd out the rest
+const CHUNKS_PER_STORY: i64 = 4;
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct ChunkNeighbor {
+    pub story: i64,
+    pub chunk_index: i32,
+    /// Cosine distance to the query, from 0 (same direction) to 2
+    pub distance: f64,
+}
+
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct StoryNeighbor {
+    #[diesel(sql_type = BigInt)]
+    pub story: i64,
+    /// Distance of the story's closest chunk
+    #[diesel(sql_type = Double)]
+    pub distance: f64,
+}
+
+/// Stores `chunks` as the embeddings of `story_id` in order, dropping any chunks left from a longer version
+pub async fn replace_story(
+    conn: &mut AsyncPgConnection,
+    story_id: i64,
+    chunks: Vec<Vector>,
+) -> QueryResult<()> {
+    let rows: Vec<Embedding> = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(chunk_index, embedding)| Embedding {
+            story: story_id,
+            chunk_index: chunk_index as i32,
+            embedding,
+        })
+        .collect();
+    conn.transaction(|conn| {
+        async move {
+            diesel::delete(
+                embeddings::table
+                    .filter(embeddings::story.eq(story_id))
+                    .filter(embeddings::chunk_index.ge(rows.len() as i32)),
+            )
+            .execute(conn)
+            .await?;
+            if !rows.is_empty() {
+                diesel::insert_into(embeddings::table)
+                    .values(&rows)
+                    .on_conflict((embeddings::story, embeddings::chunk_index))
+                    .do_update()
+                    .set((
+                        embeddings::embedding.eq(excluded(embeddings::embedding)),
+                        embeddings::embedded_at.eq(diesel::dsl::now),
+                    ))
+                    .execute(conn)
+                    .await?;
+            }
+            Ok(())
+        }
+        .scope_boxed()
+    })
+    .await
+}
+
+/// The `limit` chunks closest to `query`, closest first. Served by the HNSW index, so approximate
+pub async fn nearest_chunks(
+    conn: &mut AsyncPgConnection,
+    query: &Vector,
+    limit: i64,
+) -> QueryResult<Vec<ChunkNeighbor>> {
+    embeddings::table
+        .select((
+            embeddings::story,
+            embeddings::chunk_index,
+            embeddings::embedding.cosine_distance(query),
+        ))
+        .order(embeddings::embedding.cosine_distance(query))
+        .limit(limit)
+        .load(conn)
+        .await
+}
+
+/**
+The `limit` stories with a chunk closest to `query`, closest first.
+
+HNSW scans return at most `hnsw.ef_search` rows, so it's raised for this transaction to cover
+the chunks fetched.
+*/
+pub async fn nearest_stories(
+    conn: &mut AsyncPgConnection,
+    query: &Vector,
+    limit: i64,
+) -> QueryResult<Vec<StoryNeighbor>> {
+    let chunk_limit = limit * CHUNKS_PER_STORY;
+    let query = query.clone();
+    conn.transaction(|conn| {
+        async move {
+            sql_query(format!(
+                "SET LOCAL hnsw.ef_search = {}",
+                chunk_limit.clamp(40, 1000)
+            ))
+            .execute(conn)
+            .await?;
+            sql_query(
+                "SELECT story, min(distance) AS distance FROM (
+                     SELECT story, embedding <=> $1 AS distance
+                     FROM embeddings
+                     ORDER BY embedding <=> $1
+                     LIMIT $2
+                 ) nearest
+                 GROUP BY story
+                 ORDER BY distance
+                 LIMIT $3",
+            )
+            .bind::<pgvector::sql_types::Vector, _>(&query)
+            .bind::<BigInt, _>(chunk_limit)
+            .bind::<BigInt, _>(limit)
+            .load(conn)
+            .await
+        }
+        .scope_boxed()
+    })
+    .await
+}
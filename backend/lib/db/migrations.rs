@@ -0,0 +1,294 @@
+use diesel::backend::Backend;
+use diesel::dsl::sql;
+use diesel::migration::{Migration, MigrationSource};
+use diesel::pg::Pg;
+use diesel::sql_types::{BigInt, Bool};
+use diesel::{
+    select, sql_query, Connection, ConnectionError, PgConnection, RunQueryDsl, SqliteConnection,
+};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use log::info;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::storage::{is_sqlite_url, sqlite_path};
+
+/// Everything under `migrations/`, compiled into the binary
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+/// Everything under `migrations_embeddings/`, applied only when running with `--embed`, since it needs pgvector
+pub const EMBEDDING_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_embeddings");
+
+/// Everything under `migrations_sqlite/`, for SQLite mirrors
+pub const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+/**
+Migrations that rewrite `items` or `kids` while holding an ACCESS EXCLUSIVE lock on them, which
+blocks ingestion and every query on those tables until they finish.
+
+Startup only applies them to a DB with no items yet; otherwise they wait for `backend migrate run`.
+*/
+const HEAVY_MIGRATIONS: &[&str] = &[
+    "2026-10-19-000003_add_item_timestamps",
+    "2026-10-19-000004_add_item_search",
+    "2026-10-19-000006_partition_items_and_kids",
+];
+
+/// Advisory lock key serializing migrations across replicas starting at the same time
+const MIGRATION_LOCK_KEY: i64 = 0x0069_6e73_6d69_6772;
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("Could not connect to the DB: {0}")]
+    ConnectError(#[from] ConnectionError),
+
+    #[error(transparent)]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error("Migration failed: {0}")]
+    MigrationFailed(String),
+
+    #[error("{0:?} rewrite items under an exclusive lock, apply them with `backend migrate run` in a maintenance window")]
+    HeavyMigrationsPending(Vec<String>),
+
+    #[error(transparent)]
+    TaskJoinError(#[from] tokio::task::JoinError),
+}
+
+#[derive(Serialize, Debug)]
+pub struct MigrationStatus {
+    pub name: String,
+    pub applied: bool,
+    /// Only applied on request, like `EMBEDDING_MIGRATIONS`, so it may stay pending
+    pub optional: bool,
+}
+
+/// Every Postgres migration, so `revert` can step back over optional ones too
+struct AllPgMigrations;
+
+impl MigrationSource<Pg> for AllPgMigrations {
+    fn migrations(&self) -> diesel::migration::Result<Vec<Box<dyn Migration<Pg>>>> {
+        let mut migrations = MigrationSource::<Pg>::migrations(&MIGRATIONS)?;
+        migrations.extend(MigrationSource::<Pg>::migrations(&EMBEDDING_MIGRATIONS)?);
+        Ok(migrations)
+    }
+}
+
+/// A blocking connection for the migration harness, which is sync-only
+enum MigrationConnection {
+    Pg(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+/// Applies every embedded migration the DB hasn't seen yet, returning their names
+pub async fn run_pending(db_url: &str) -> Result<Vec<String>, MigrationError> {
+    with_lock(db_url, |conn| match conn {
+        MigrationConnection::Pg(conn) => apply(conn, MIGRATIONS),
+        MigrationConnection::Sqlite(conn) => apply(conn, SQLITE_MIGRATIONS),
+    })
+    .await
+}
+
+/// Like `run_pending`, but refuses to apply `HEAVY_MIGRATIONS` to a DB that already holds items
+pub async fn run_pending_at_startup(db_url: &str) -> Result<Vec<String>, MigrationError> {
+    with_lock(db_url, |conn| match conn {
+        MigrationConnection::Pg(conn) => {
+            let heavy: Vec<String> = conn
+                .pending_migrations(MIGRATIONS)
+                .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?
+                .iter()
+                .map(|migration| migration.name().to_string())
+                .filter(|name| HEAVY_MIGRATIONS.contains(&name.as_str()))
+                .collect();
+            if !heavy.is_empty() && has_items(conn)? {
+                return Err(MigrationError::HeavyMigrationsPending(heavy));
+            }
+            apply(conn, MIGRATIONS)
+        }
+        MigrationConnection::Sqlite(conn) => apply(conn, SQLITE_MIGRATIONS),
+    })
+    .await
+}
+
+/// Applies the pending `EMBEDDING_MIGRATIONS`, returning their names. Postgres only
+pub async fn run_embedding_migrations(db_url: &str) -> Result<Vec<String>, MigrationError> {
+    with_lock(db_url, |conn| match conn {
+        MigrationConnection::Pg(conn) => apply(conn, EMBEDDING_MIGRATIONS),
+        MigrationConnection::Sqlite(_) => Err(MigrationError::MigrationFailed(
+            "embeddings need Postgres".to_string(),
+        )),
+    })
+    .await
+}
+
+/// Lists embedded migrations in order, with whether each one has been applied
+pub async fn status(db_url: &str) -> Result<Vec<MigrationStatus>, MigrationError> {
+    with_lock(db_url, |conn| match conn {
+        MigrationConnection::Pg(conn) => {
+            list(conn, &[(MIGRATIONS, false), (EMBEDDING_MIGRATIONS, true)])
+        }
+        MigrationConnection::Sqlite(conn) => list(conn, &[(SQLITE_MIGRATIONS, false)]),
+    })
+    .await
+}
+
+/// Rolls back the `steps` most recently applied migrations, returning their versions
+pub async fn revert(db_url: &str, steps: usize) -> Result<Vec<String>, MigrationError> {
+    with_lock(db_url, move |conn| match conn {
+        MigrationConnection::Pg(conn) => revert_last(conn, || AllPgMigrations, steps),
+        MigrationConnection::Sqlite(conn) => revert_last(conn, || SQLITE_MIGRATIONS, steps),
+    })
+    .await
+}
+
+fn apply<DB: Backend>(
+    conn: &mut impl MigrationHarness<DB>,
+    migrations: EmbeddedMigrations,
+) -> Result<Vec<String>, MigrationError> {
+    let applied = conn
+        .run_pending_migrations(migrations)
+        .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?;
+    Ok(applied.iter().map(|version| version.to_string()).collect())
+}
+
+/// Whether `items` exists and has rows, i.e. whether rewriting it would take a while
+fn has_items(conn: &mut PgConnection) -> Result<bool, MigrationError> {
+    let exists = select(sql::<Bool>("to_regclass('items') IS NOT NULL")).get_result(conn)?;
+    Ok(exists && sql_query("SELECT 1 FROM items LIMIT 1").execute(conn)? > 0)
+}
+
+/// `sets` pairs each migration set with whether it's optional
+fn list<DB: Backend>(
+    conn: &mut impl MigrationHarness<DB>,
+    sets: &[(EmbeddedMigrations, bool)],
+) -> Result<Vec<MigrationStatus>, MigrationError> {
+    let applied = conn
+        .applied_migrations()
+        .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?;
+    let mut migrations = Vec::new();
+    for (set, optional) in sets {
+        let set = MigrationSource::<DB>::migrations(set)
+            .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?;
+        migrations.extend(set.into_iter().map(|migration| (migration, *optional)));
+    }
+    migrations.sort_by(|(a, _), (b, _)| a.name().version().cmp(&b.name().version()));
+    Ok(migrations
+        .iter()
+        .map(|(migration, optional)| MigrationStatus {
+            name: migration.name().to_string(),
+            applied: applied.contains(&migration.name().version()),
+            optional: *optional,
+        })
+        .collect())
+}
+
+/// `migrations` hands out the source for each step, since reverting consumes it
+fn revert_last<DB: Backend, S: MigrationSource<DB>>(
+    conn: &mut impl MigrationHarness<DB>,
+    migrations: impl Fn() -> S,
+    steps: usize,
+) -> Result<Vec<String>, MigrationError> {
+    let mut reverted = Vec::with_capacity(steps);
+    for _ in 0..steps {
+        if conn
+            .applied_migrations()
+            .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?
+            .is_empty()
+        {
+            break;
+        }
+        let version = conn
+            .revert_last_migration(migrations())
+            .map_err(|err| MigrationError::MigrationFailed(err.to_string()))?;
+        info!("Reverted migration {}", version);
+        reverted.push(version.to_string());
+    }
+    Ok(reverted)
+}
+
+/**
+Runs `f` on a fresh blocking connection holding the migration lock.
+
+The migration harness is sync-only, so this runs on the blocking pool. On Postgres, the lock is
+session-level and goes away with the connection, even if `f` fails. A SQLite file has a single
+writer, so it needs no lock.
+*/
+async fn with_lock<T, F>(db_url: &str, f: F) -> Result<T, MigrationError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut MigrationConnection) -> Result<T, MigrationError> + Send + 'static,
+{
+    let db_url = db_url.to_string();
+    tokio::task::spawn_blocking(move || {
+        if is_sqlite_url(&db_url) {
+            let conn = SqliteConnection::establish(sqlite_path(&db_url))?;
+            return f(&mut MigrationConnection::Sqlite(conn));
+        }
+        let mut conn = PgConnection::establish(&db_url)?;
+        sql_query("SELECT pg_advisory_lock($1)")
+            .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+            .execute(&mut conn)?;
+        f(&mut MigrationConnection::Pg(conn))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_pending;
+    use crate::db::models::{Item, Kid};
+    use crate::storage::{SqliteStorage, Storage};
+    use diesel::connection::SimpleConnection;
+    use diesel::{Connection, SqliteConnection};
+
+    /// The schema hn-to-sqlite creates, which has no unique constraint on `kids`
+    const LEGACY_SCHEMA: &str = "
+        CREATE TABLE items (
+            id INTEGER PRIMARY KEY, deleted BOOLEAN, type TEXT, by TEXT, time INTEGER,
+            text TEXT, dead BOOLEAN, parent INTEGER, poll INTEGER, url TEXT, score INTEGER,
+            title TEXT, parts TEXT, descendants INTEGER
+        ) WITHOUT ROWID;
+        CREATE TABLE kids (
+            item INTEGER, kid INTEGER, display_order INTEGER,
+            FOREIGN KEY (item) REFERENCES items (id), FOREIGN KEY (kid) REFERENCES items (id)
+        );
+        CREATE TABLE users (
+            id TEXT PRIMARY KEY, created INTEGER, karma INTEGER, about TEXT, submitted TEXT
+        );
+        INSERT INTO items (id, type, title) VALUES (1, 'story', 'Legacy'), (2, 'comment', NULL);
+        INSERT INTO kids (item, kid, display_order) VALUES (1, 2, 0), (1, 2, 0);
+    ";
+
+    #[tokio::test]
+    async fn sqlite_migrations_adopt_a_legacy_file() {
+        let path = std::env::temp_dir().join(format!("backend-legacy-{}.db", std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        let db_url = format!("sqlite://{}", path.display());
+        SqliteConnection::establish(path.to_str().unwrap())
+            .unwrap()
+            .batch_execute(LEGACY_SCHEMA)
+            .unwrap();
+
+        run_pending(&db_url).await.unwrap();
+
+        let storage = SqliteStorage::open(&db_url).unwrap();
+        let story = Item {
+            id: 1,
+            type_: Some("story".to_string()),
+            title: Some("Updated".to_string()),
+            ..Default::default()
+        };
+        let kids = [Kid {
+            item: 1,
+            kid: 2,
+            display_order: Some(0),
+        }];
+        storage.upsert_items(&[story], &kids).await.unwrap();
+
+        let stored = storage.get_items(&[1]).await.unwrap();
+        assert_eq!(stored[0].title.as_deref(), Some("Updated"));
+        assert_eq!(storage.get_kids(&[1]).await.unwrap().len(), 1);
+    }
+}
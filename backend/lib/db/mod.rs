@@ -1,2 +1,8 @@
+pub mod embeddings;
+pub mod migrations;
 pub mod models;
+pub mod partitions;
+pub mod pool;
 pub mod schema;
+pub mod search;
+pub mod threads;
@@ -1,15 +1,28 @@
 use crate::firebase_listener::listener;
 
-use super::schema::items;
-use diesel::dsl::{delete, insert_into};
-use diesel::pg::PgConnection;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
+use pgvector::Vector;
+use serde::{Deserialize, Serialize};
 
-#[derive(Queryable, Identifiable, Insertable, AsChangeset)]
+/// An `items` row. Derived columns that need their own SQL types, like `path`, aren't mapped
+#[derive(
+    Queryable,
+    QueryableByName,
+    Selectable,
+    Identifiable,
+    Insertable,
+    AsChangeset,
+    Clone,
+    Default,
+    Serialize,
+    Deserialize,
+)]
 #[diesel(table_name = super::schema::items)]
 pub struct Item {
     pub id: i64,
     pub deleted: Option<bool>,
+    #[serde(rename = "type")]
     pub type_: Option<String>,
     pub by: Option<String>,
     pub time: Option<i64>,
@@ -22,18 +35,43 @@ pub struct Item {
     pub title: Option<String>,
     pub parts: Option<String>,
     pub descendants: Option<i64>,
+    /// Root story of the thread. Maintained by the sync service, not from HN
+    pub story_id: Option<i64>,
+    /// Distance from the root story, which has depth 0
+    pub depth: Option<i32>,
+    /// `time` as a timestamp. Generated by Postgres, so always `None` on items not read from the DB
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
 }
 
 impl Item {
-    pub fn create(conn: &mut PgConnection, new_item: &Item) -> QueryResult<usize> {
-        insert_into(items::table).values(new_item).execute(conn)
-    }
-
-    pub fn delete(conn: &mut PgConnection, item_id: i64) -> QueryResult<usize> {
-        delete(items::table.filter(items::id.eq(item_id))).execute(conn)
+    /// Names of the columns whose values differ between `self` and `other`
+    pub fn changed_fields(&self, other: &Item) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        macro_rules! compare {
+            ($($field:ident => $column:literal),*) => {
+                $(if self.$field != other.$field {
+                    changed.push($column);
+                })*
+            };
+        }
+        compare!(
+            deleted => "deleted",
+            type_ => "type",
+            by => "by",
+            time => "time",
+            text => "text",
+            dead => "dead",
+            parent => "parent",
+            poll => "poll",
+            url => "url",
+            score => "score",
+            title => "title",
+            parts => "parts",
+            descendants => "descendants"
+        );
+        changed
     }
-
-    // Add additional methods for querying the items table...
 }
 
 impl From<listener::Item> for Item {
@@ -53,14 +91,58 @@ impl From<listener::Item> for Item {
             title: fb_item.title,
             parts: fb_item.parts,
             descendants: fb_item.descendants,
+            story_id: None,
+            depth: None,
+            created_at: None,
         }
     }
 }
 
-#[derive(Queryable, Insertable, AsChangeset)]
+#[derive(Queryable, Insertable, AsChangeset, Clone, Serialize, Deserialize)]
 #[diesel(table_name = super::schema::kids)]
 pub struct Kid {
     pub item: i64,
     pub kid: i64,
     pub display_order: Option<i64>,
 }
+
+/// One chunk of a story's discussion, embedded. `embedded_at` is set by Postgres
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = super::schema::embeddings)]
+pub struct Embedding {
+    pub story: i64,
+    pub chunk_index: i32,
+    pub embedding: Vector,
+}
+
+/// A `users` row, mirroring HN's user object
+#[derive(
+    Queryable, Selectable, Identifiable, Insertable, AsChangeset, Clone, Serialize, Deserialize,
+)]
+#[diesel(table_name = super::schema::users, treat_none_as_null = true)]
+pub struct User {
+    pub id: String,
+    pub created: Option<i64>,
+    pub karma: Option<i64>,
+    pub about: Option<String>,
+    /// Ids of the user's stories, polls and comments, as stored by the importer
+    pub submitted: Option<String>,
+}
+
+impl From<listener::User> for User {
+    fn from(fb_user: listener::User) -> Self {
+        Self {
+            id: fb_user.id,
+            created: fb_user.created,
+            karma: fb_user.karma,
+            about: fb_user.about,
+            // Comma-separated, like the importer writes them
+            submitted: fb_user.submitted.map(|ids| {
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Text};
+use diesel::{QueryResult, QueryableByName};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::Serialize;
+use std::ops::RangeInclusive;
+
+/// Ids per partition. Must match `create_item_partitions` in the migrations
+pub const PARTITION_SIZE: i64 = 1_000_000;
+
+#[derive(QueryableByName)]
+struct CreatedPartition {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+/// One id range of `items` and `kids`, which share partition bounds
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct Partition {
+    #[diesel(sql_type = BigInt)]
+    pub index: i64,
+    /// Planner's row count estimate for the items partition, as of its last analyze
+    #[diesel(sql_type = BigInt)]
+    pub items_estimate: i64,
+    /// On-disk size of the items and kids partitions, including indexes and TOAST
+    #[diesel(sql_type = BigInt)]
+    pub total_bytes: i64,
+}
+
+/// Maintenance that can run on one partition at a time
+#[derive(Clone, Copy, Debug)]
+pub enum Maintenance {
+    Vacuum,
+    Analyze,
+    /// Rebuilds indexes without blocking writes
+    Reindex,
+}
+
+/// Ids held by partition `index`. HN ids start at 1
+pub fn id_range(index: i64) -> RangeInclusive<i64> {
+    (index * PARTITION_SIZE).max(1)..=(index + 1) * PARTITION_SIZE - 1
+}
+
+fn table_name(table: &str, index: i64) -> String {
+    format!("{}_p{:04}", table, index)
+}
+
+/**
+Creates any missing partitions up to one whole partition past `max_id`, returning their names.
+
+Inserts into a range without a partition fail, so callers run this whenever they learn of a new
+`maxitem`. The headroom leaves weeks of new ids before the next call is strictly needed.
+*/
+pub async fn ensure_partitions(
+    conn: &mut AsyncPgConnection,
+    max_id: i64,
+) -> QueryResult<Vec<String>> {
+    let created: Vec<CreatedPartition> = sql_query("SELECT create_item_partitions($1) AS name")
+        .bind::<BigInt, _>(max_id + PARTITION_SIZE)
+        .load(conn)
+        .await?;
+    Ok(created
+        .into_iter()
+        .map(|partition| partition.name)
+        .collect())
+}
+
+/// Every partition, in id order
+pub async fn list(conn: &mut AsyncPgConnection) -> QueryResult<Vec<Partition>> {
+    sql_query(
+        "SELECT substring(c.relname FROM 'items_p(\\d+)')::bigint AS index,
+                greatest(c.reltuples, 0)::bigint AS items_estimate,
+                (pg_total_relation_size(c.oid)
+                 + coalesce(pg_total_relation_size(to_regclass(replace(c.relname, 'items_', 'kids_'))), 0)
+                )::bigint AS total_bytes
+         FROM pg_inherits i
+         JOIN pg_class c ON c.oid = i.inhrelid
+         WHERE i.inhparent = 'items'::regclass
+         ORDER BY index",
+    )
+    .load(conn)
+    .await
+}
+
+/// Runs `maintenance` on the items and kids partitions with index `index`
+pub async fn maintain(
+    conn: &mut AsyncPgConnection,
+    index: i64,
+    maintenance: Maintenance,
+) -> QueryResult<()> {
+    for table in ["items", "kids"] {
+        // Identifiers can't be bound, but the name is built from an integer
+        let name = table_name(table, index);
+        let statement = match maintenance {
+            Maintenance::Vacuum => format!("VACUUM (ANALYZE) {}", name),
+            Maintenance::Analyze => format!("ANALYZE {}", name),
+            Maintenance::Reindex => format!("REINDEX TABLE CONCURRENTLY {}", name),
+        };
+        sql_query(statement).execute(conn).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{id_range, table_name, PARTITION_SIZE};
+
+    #[test]
+    fn id_range_skips_id_zero() {
+        assert_eq!(id_range(0), 1..=PARTITION_SIZE - 1);
+    }
+
+    #[test]
+    fn id_ranges_are_contiguous() {
+        assert_eq!(id_range(42), 42_000_000..=42_999_999);
+        for index in 0..5 {
+            assert_eq!(*id_range(index).end() + 1, *id_range(index + 1).start());
+        }
+    }
+
+    #[test]
+    fn table_name_matches_create_item_partitions() {
+        // The SQL function names partitions with lpad(n, 4, '0')
+        assert_eq!(table_name("items", 42), "items_p0042");
+        assert_eq!(table_name("kids", 0), "kids_p0000");
+    }
+}
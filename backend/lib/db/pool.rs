@@ -0,0 +1,72 @@
+use deadpool::Runtime;
+use diesel::{sql_query, ConnectionError};
+use diesel_async::pooled_connection::deadpool::{BuildError, Hook, HookError, Pool};
+use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use futures::FutureExt;
+use serde::Serialize;
+
+use crate::config::DbPoolConfig;
+
+/// How full the pool is, as served on `/health/db`
+#[derive(Serialize, Debug)]
+pub struct PoolStats {
+    pub max_size: usize,
+    /// Connections open, idle or not
+    pub size: usize,
+    pub in_use: usize,
+    pub idle: usize,
+    /// Callers queued for a connection. Anything above 0 for long means the pool is too small
+    pub waiting: usize,
+}
+
+/**
+Builds the Postgres pool from `config`.
+
+Each new connection gets `config.statement_timeout` as its `statement_timeout`, and connections
+older than `config.max_age` are closed instead of reused, so server-side memory is released and
+DNS changes are picked up over time.
+*/
+pub fn build(db_url: &str, config: &DbPoolConfig) -> Result<Pool<AsyncPgConnection>, BuildError> {
+    let statement_timeout = config.statement_timeout;
+    let manager =
+        AsyncDieselConnectionManager::<AsyncPgConnection>::new_with_setup(db_url, move |url| {
+            async move {
+                let mut conn = AsyncPgConnection::establish(url).await?;
+                if let Some(timeout) = statement_timeout {
+                    sql_query(format!("SET statement_timeout = {}", timeout.as_millis()))
+                        .execute(&mut conn)
+                        .await
+                        .map_err(ConnectionError::CouldntSetupConfiguration)?;
+                }
+                Ok(conn)
+            }
+            .boxed()
+        });
+    let mut builder = Pool::builder(manager)
+        .max_size(config.max_size)
+        .wait_timeout(Some(config.wait_timeout))
+        .runtime(Runtime::Tokio1);
+    if let Some(max_age) = config.max_age {
+        builder = builder.pre_recycle(Hook::sync_fn(move |_, metrics| {
+            if metrics.age() > max_age {
+                return Err(HookError::Continue(None));
+            }
+            Ok(())
+        }));
+    }
+    builder.build()
+}
+
+pub fn stats(pool: &Pool<AsyncPgConnection>) -> PoolStats {
+    let status = pool.status();
+    // `available` goes negative by the number of callers waiting once every connection is taken
+    let idle = status.available.max(0) as usize;
+    PoolStats {
+        max_size: status.max_size,
+        size: status.size,
+        in_use: status.size - idle,
+        idle,
+        waiting: (-status.available).max(0) as usize,
+    }
+}
@@ -1,6 +1,52 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "ltree"))]
+    pub struct Ltree;
+
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "tsvector", schema = "pg_catalog"))]
+    pub struct Tsvector;
+}
+
+diesel::table! {
+    changed_items (day, id) {
+        day -> Date,
+        id -> Int8,
+    }
+}
+
 diesel::table! {
+    changed_users (day, id) {
+        day -> Date,
+        id -> Text,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use pgvector::sql_types::Vector;
+
+    embeddings (story, chunk_index) {
+        story -> Int8,
+        chunk_index -> Int4,
+        embedding -> Vector,
+        embedded_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    empty_items (id) {
+        id -> Int8,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::Ltree;
+    use super::sql_types::Tsvector;
+
     items (id) {
         id -> Int8,
         deleted -> Nullable<Bool>,
@@ -17,6 +63,11 @@ diesel::table! {
         title -> Nullable<Text>,
         parts -> Nullable<Text>,
         descendants -> Nullable<Int8>,
+        story_id -> Nullable<Int8>,
+        depth -> Nullable<Int4>,
+        path -> Nullable<Ltree>,
+        created_at -> Nullable<Timestamptz>,
+        search_vector -> Nullable<Tsvector>,
     }
 }
 
@@ -28,6 +79,13 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    pending_updates (id) {
+        id -> Int8,
+        queued_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Text,
@@ -38,10 +96,16 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(embeddings -> items (story));
 diesel::joinable!(kids -> items (item));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    changed_items,
+    changed_users,
+    embeddings,
+    empty_items,
     items,
     kids,
+    pending_updates,
     users,
 );
@@ -0,0 +1,73 @@
+use chrono::{DateTime, Utc};
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Float4, Nullable, Text, Timestamptz};
+use diesel::{QueryResult, QueryableByName};
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+use serde::{Deserialize, Serialize};
+
+use super::models::Item;
+
+pub const DEFAULT_LIMIT: i64 = 20;
+pub const MAX_LIMIT: i64 = 100;
+
+/**
+A full-text search over item titles and text.
+
+`q` uses web search syntax: `"quoted phrases"` must match in order, `or` between terms
+matches either, and a leading `-` excludes a term. Everything else is optional.
+*/
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub by: Option<String>,
+    /// Only items created at or after this time
+    pub after: Option<DateTime<Utc>>,
+    /// Only items created before this time
+    pub before: Option<DateTime<Utc>>,
+    /// Defaults to `DEFAULT_LIMIT`, capped at `MAX_LIMIT`
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(QueryableByName, Serialize)]
+pub struct SearchHit {
+    #[diesel(embed)]
+    #[serde(flatten)]
+    pub item: Item,
+    /// `ts_rank` of the match, where title matches weigh more than text
+    #[diesel(sql_type = Float4)]
+    pub rank: f32,
+}
+
+/// Live items matching `query`, best match first. Served by the `search_vector` GIN index
+pub async fn search(
+    conn: &mut AsyncPgConnection,
+    query: &SearchQuery,
+) -> QueryResult<Vec<SearchHit>> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = query.offset.unwrap_or(0).max(0);
+    sql_query(
+        "SELECT i.*, i.type AS type_, ts_rank(i.search_vector, q) AS rank
+         FROM items i, websearch_to_tsquery('english', $1) q
+         WHERE i.search_vector @@ q
+           AND i.deleted IS NOT TRUE
+           AND i.dead IS NOT TRUE
+           AND ($2::text IS NULL OR i.type = $2)
+           AND ($3::text IS NULL OR i.by = $3)
+           AND ($4::timestamptz IS NULL OR i.created_at >= $4)
+           AND ($5::timestamptz IS NULL OR i.created_at < $5)
+         ORDER BY rank DESC, i.id DESC
+         LIMIT $6 OFFSET $7",
+    )
+    .bind::<Text, _>(&query.q)
+    .bind::<Nullable<Text>, _>(query.type_.as_deref())
+    .bind::<Nullable<Text>, _>(query.by.as_deref())
+    .bind::<Nullable<Timestamptz>, _>(query.after)
+    .bind::<Nullable<Timestamptz>, _>(query.before)
+    .bind::<BigInt, _>(limit)
+    .bind::<BigInt, _>(offset)
+    .load(conn)
+    .await
+}
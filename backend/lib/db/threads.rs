@@ -0,0 +1,111 @@
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt};
+use diesel::QueryResult;
+use diesel_async::{AsyncPgConnection, RunQueryDsl};
+
+use super::models::Item;
+
+/**
+Sets `story_id` and `depth` for the given items, then walks down `parent` and `poll` links to their descendants.
+
+An item resolves once it's a root (no `parent` or `poll`) or its parent is resolved. Walking down
+covers out-of-order arrivals: children ingested before their parent get resolved when the parent
+shows up. The walk stops at rows that are already correct, so re-resolving a story is cheap.
+*/
+const RESOLVE_STORY_IDS: &str = "
+WITH RECURSIVE tree (id, story_id, depth) AS (
+    SELECT i.id,
+           CASE WHEN p.id IS NULL THEN i.id ELSE p.story_id END,
+           CASE WHEN p.id IS NULL THEN 0 ELSE p.depth + 1 END
+    FROM items i
+    LEFT JOIN items p ON p.id = COALESCE(i.parent, i.poll)
+    WHERE i.id = ANY($1)
+      AND (COALESCE(i.parent, i.poll) IS NULL OR p.story_id IS NOT NULL)
+  UNION ALL
+    SELECT c.id, t.story_id, t.depth + 1
+    FROM items c
+    JOIN tree t ON COALESCE(c.parent, c.poll) = t.id
+    WHERE c.story_id IS DISTINCT FROM t.story_id
+       OR c.depth IS DISTINCT FROM t.depth + 1
+)
+UPDATE items
+SET story_id = tree.story_id, depth = tree.depth
+FROM tree
+WHERE items.id = tree.id
+  AND (items.story_id IS DISTINCT FROM tree.story_id OR items.depth IS DISTINCT FROM tree.depth)
+";
+
+/**
+Sets `path` for the given items, then walks down `kids` to their descendants.
+
+A root's path is its own id; each level below appends the item's zero-padded `display_order`
+in its parent's `kids`, so ordering by `path` lists a thread depth-first in display order.
+An item resolves once its parent has a path and lists it in `kids`. As with story ids, walking
+down picks up late parents and reordered kids, and stops at rows that are already correct.
+*/
+const RESOLVE_PATHS: &str = "
+WITH RECURSIVE tree (id, path) AS (
+    SELECT i.id,
+           CASE WHEN i.parent IS NULL THEN text2ltree(i.id::text)
+                ELSE p.path || lpad(k.display_order::text, 6, '0') END
+    FROM items i
+    LEFT JOIN items p ON p.id = i.parent
+    LEFT JOIN kids k ON k.item = i.parent AND k.kid = i.id
+    WHERE i.id = ANY($1)
+      AND (i.parent IS NULL OR (p.path IS NOT NULL AND k.display_order IS NOT NULL))
+  UNION ALL
+    SELECT c.id, t.path || lpad(k.display_order::text, 6, '0')
+    FROM tree t
+    JOIN kids k ON k.item = t.id
+    JOIN items c ON c.id = k.kid
+    WHERE k.display_order IS NOT NULL
+      AND c.path IS DISTINCT FROM t.path || lpad(k.display_order::text, 6, '0')
+)
+UPDATE items
+SET path = tree.path
+FROM tree
+WHERE items.id = tree.id
+  AND items.path IS DISTINCT FROM tree.path
+";
+
+/// Clears `path` for the given items and everything below them, e.g. once they're detached
+const CLEAR_PATHS: &str = "
+UPDATE items
+SET path = NULL
+WHERE path <@ ARRAY(SELECT path FROM items WHERE id = ANY($1) AND path IS NOT NULL)
+";
+
+/// Items whose parent hasn't been ingested yet keep `story_id` NULL until it is
+pub async fn resolve_story_ids(conn: &mut AsyncPgConnection, ids: &[i64]) -> QueryResult<usize> {
+    sql_query(RESOLVE_STORY_IDS)
+        .bind::<Array<BigInt>, _>(ids)
+        .execute(conn)
+        .await
+}
+
+/// Must run after the batch's `kids` rows are written, since paths are built from them
+pub async fn resolve_paths(conn: &mut AsyncPgConnection, ids: &[i64]) -> QueryResult<usize> {
+    sql_query(RESOLVE_PATHS)
+        .bind::<Array<BigInt>, _>(ids)
+        .execute(conn)
+        .await
+}
+
+pub async fn clear_paths(conn: &mut AsyncPgConnection, ids: &[i64]) -> QueryResult<usize> {
+    sql_query(CLEAR_PATHS)
+        .bind::<Array<BigInt>, _>(ids)
+        .execute(conn)
+        .await
+}
+
+/// `root_id` and everything below it, depth-first in display order. Served by the `path` GiST index
+pub async fn subtree(conn: &mut AsyncPgConnection, root_id: i64) -> QueryResult<Vec<Item>> {
+    sql_query(
+        "SELECT i.*, i.type AS type_ FROM items i
+         WHERE i.path <@ (SELECT path FROM items WHERE id = $1)
+         ORDER BY i.path",
+    )
+    .bind::<BigInt, _>(root_id)
+    .load(conn)
+    .await
+}
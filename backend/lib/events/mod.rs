@@ -0,0 +1,41 @@
+use tokio::sync::broadcast;
+
+use crate::change_feed::ChangeOp;
+
+/// Published once per item after `upload_items` commits a change to it
+#[derive(Debug, Clone)]
+pub struct ItemChanged {
+    pub id: i64,
+    /// HN item type, e.g. `story` or `comment`
+    pub kind: Option<String>,
+    pub op: ChangeOp,
+    /// Columns whose values changed. For inserts, every column that has a value
+    pub fields_changed: Vec<&'static str>,
+}
+
+/**
+In-process broadcast of item changes written by the sync workers.
+
+Subscribers that fall more than the bus capacity behind miss events
+(`RecvError::Lagged`) rather than slowing down the writers.
+*/
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ItemChanged>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ItemChanged> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn publish(&self, event: ItemChanged) {
+        // Errors only when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+}
@@ -0,0 +1,243 @@
+use chrono::{Duration, NaiveDate};
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use super::snapshot::{CompressedFile, RowCounts, SnapshotFile};
+use super::{write_manifest, ExportError};
+use crate::db::models::{Item, Kid, User};
+use crate::storage::Storage;
+
+/// Rows read or applied at a time
+const PAGE_SIZE: usize = 10_000;
+
+/// The rolling manifest at the root of a delta directory
+pub const MANIFEST_NAME: &str = "deltas.json";
+
+/// One day of changes, as listed in `deltas.json`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeltaDay {
+    pub date: NaiveDate,
+    pub rows: RowCounts,
+    pub items: SnapshotFile,
+    pub kids: SnapshotFile,
+    pub users: SnapshotFile,
+}
+
+impl DeltaDay {
+    fn files(&self) -> [&SnapshotFile; 3] {
+        [&self.items, &self.kids, &self.users]
+    }
+}
+
+/// `deltas.json`: the days currently published, oldest first
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct DeltaManifest {
+    pub days: Vec<DeltaDay>,
+}
+
+/// What `apply` brought into the DB
+#[derive(Serialize, Debug, Default)]
+pub struct ApplySummary {
+    pub days: Vec<NaiveDate>,
+    pub items: usize,
+    pub kids: usize,
+    pub users: usize,
+}
+
+fn read_manifest(dir: &Path) -> Result<DeltaManifest, ExportError> {
+    Ok(serde_json::from_slice(&fs::read(dir.join(MANIFEST_NAME))?)?)
+}
+
+/// Rows of a zstd JSONL file, parsed as they're read
+fn read_lines<T: DeserializeOwned>(
+    path: &Path,
+) -> Result<impl Iterator<Item = Result<T, ExportError>>, ExportError> {
+    let reader = BufReader::new(zstd::Decoder::new(File::open(path)?)?);
+    Ok(reader.lines().map(|line| Ok(serde_json::from_str(&line?)?)))
+}
+
+/// Fails unless the file in `dir` hashes to its manifest entry
+fn verify(dir: &Path, file: &SnapshotFile) -> Result<(), ExportError> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(dir.join(&file.name))?, &mut hasher)?;
+    if format!("{:x}", hasher.finalize()) != file.sha256 {
+        return Err(ExportError::ChecksumMismatch(file.name.clone()));
+    }
+    Ok(())
+}
+
+/**
+Writes the items and users recorded as changed on `date` to `hn-delta-<date>-{items,kids,users}.jsonl.zst`
+under `out_dir`, and adds the day to `deltas.json`. Items come with all their `kids` rows.
+
+The manifest keeps the `retain_days` days up to `date`. Older days are dropped from it with their
+files, and their records in `changed_items` and `changed_users` are pruned.
+*/
+pub async fn write(
+    storage: &dyn Storage,
+    out_dir: &Path,
+    date: NaiveDate,
+    retain_days: u32,
+    zstd_level: i32,
+) -> Result<DeltaManifest, ExportError> {
+    fs::create_dir_all(out_dir)?;
+    let file = |table: &str| {
+        CompressedFile::create(
+            out_dir,
+            format!("hn-delta-{}-{}.jsonl.zst", date.format("%Y%m%d"), table),
+            zstd_level,
+        )
+    };
+    let (mut items_file, mut kids_file, mut users_file) =
+        (file("items")?, file("kids")?, file("users")?);
+    let mut rows = RowCounts::default();
+
+    let item_ids = storage.changed_item_ids(date).await?;
+    for ids in item_ids.chunks(PAGE_SIZE) {
+        let items = storage.get_items(ids).await?;
+        let mut kids = storage.get_kids(ids).await?;
+        kids.sort_by_key(|kid| (kid.item, kid.kid));
+        for item in &items {
+            items_file.write_line(item)?;
+        }
+        for kid in &kids {
+            kids_file.write_line(kid)?;
+        }
+        rows.items += items.len();
+        rows.kids += kids.len();
+    }
+
+    let user_ids = storage.changed_user_ids(date).await?;
+    for ids in user_ids.chunks(PAGE_SIZE) {
+        let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let users = storage.get_users(&ids).await?;
+        for user in &users {
+            users_file.write_line(user)?;
+        }
+        rows.users += users.len();
+    }
+
+    let day = DeltaDay {
+        date,
+        rows,
+        items: items_file.finish()?,
+        kids: kids_file.finish()?,
+        users: users_file.finish()?,
+    };
+    info!(
+        "Delta for {}: {} items, {} kids, {} users",
+        date, day.rows.items, day.rows.kids, day.rows.users
+    );
+
+    let mut manifest = match read_manifest(out_dir) {
+        Ok(manifest) => manifest,
+        Err(ExportError::IoError(err)) if err.kind() == io::ErrorKind::NotFound => {
+            DeltaManifest::default()
+        }
+        Err(err) => return Err(err),
+    };
+    manifest.days.retain(|d| d.date != date);
+    manifest.days.push(day);
+    manifest.days.sort_by_key(|d| d.date);
+
+    let first_kept = first_kept_day(date, retain_days);
+    let (expired, kept): (Vec<DeltaDay>, Vec<DeltaDay>) =
+        manifest.days.into_iter().partition(|d| d.date < first_kept);
+    manifest.days = kept;
+    // The manifest goes first, so it never lists a deleted file
+    write_manifest(out_dir, MANIFEST_NAME, &manifest)?;
+    for day in expired {
+        for file in day.files() {
+            match fs::remove_file(out_dir.join(&file.name)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        info!("Dropped the delta for {}", day.date);
+    }
+    storage.prune_changes(first_kept).await?;
+    Ok(manifest)
+}
+
+/// Oldest day a manifest keeping `retain_days` days up to `date` still lists
+fn first_kept_day(date: NaiveDate, retain_days: u32) -> NaiveDate {
+    date - Duration::days(i64::from(retain_days) - 1)
+}
+
+/**
+Applies the days in `dir`'s `deltas.json` to `storage` in order, optionally only those from `since` on.
+
+Every file is checked against its checksum first. Rows are upserted, so applying a day twice is
+harmless, and an interrupted run can be repeated.
+*/
+pub async fn apply(
+    storage: &dyn Storage,
+    dir: &Path,
+    since: Option<NaiveDate>,
+) -> Result<ApplySummary, ExportError> {
+    let manifest = read_manifest(dir)?;
+    let mut summary = ApplySummary::default();
+    for day in &manifest.days {
+        if since.is_some_and(|since| day.date < since) {
+            continue;
+        }
+        for file in day.files() {
+            verify(dir, file)?;
+        }
+
+        let mut kids: HashMap<i64, Vec<Kid>> = HashMap::new();
+        for kid in read_lines::<Kid>(&dir.join(&day.kids.name))? {
+            let kid = kid?;
+            kids.entry(kid.item).or_default().push(kid);
+        }
+        let items: Vec<Item> = read_lines(&dir.join(&day.items.name))?.collect::<Result<_, _>>()?;
+        // A partitioned copy rejects ids beyond its last partition, and the delta may reach past it
+        if let Some(max_id) = items.iter().map(|item| item.id).max() {
+            storage.ensure_partitions(max_id).await?;
+        }
+        for page in items.chunks(PAGE_SIZE) {
+            let page_kids: Vec<Kid> = page
+                .iter()
+                .flat_map(|item| kids.remove(&item.id).unwrap_or_default())
+                .collect();
+            storage.upsert_items(page, &page_kids).await?;
+            summary.items += page.len();
+            summary.kids += page_kids.len();
+        }
+
+        for user in read_lines::<User>(&dir.join(&day.users.name))? {
+            storage.upsert_user(&user?).await?;
+            summary.users += 1;
+        }
+        info!("Applied the delta for {}", day.date);
+        summary.days.push(day.date);
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::first_kept_day;
+    use chrono::NaiveDate;
+
+    fn day(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn first_kept_day_keeps_retain_days_days_including_date() {
+        assert_eq!(first_kept_day(day("2024-01-31"), 30), day("2024-01-02"));
+        assert_eq!(first_kept_day(day("2024-01-31"), 1), day("2024-01-31"));
+    }
+
+    #[test]
+    fn first_kept_day_crosses_month_and_leap_day() {
+        assert_eq!(first_kept_day(day("2024-03-01"), 2), day("2024-02-29"));
+    }
+}
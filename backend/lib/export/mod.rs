@@ -0,0 +1,171 @@
+use ::parquet::errors::ParquetError;
+use arrow_schema::ArrowError;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use thiserror::Error;
+
+pub mod delta;
+pub mod parquet;
+pub mod snapshot;
+
+use crate::db::migrations::MigrationError;
+use crate::storage::{Storage, StorageError};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+
+    #[error(transparent)]
+    ParquetError(#[from] ParquetError),
+
+    #[error(transparent)]
+    ArrowError(#[from] ArrowError),
+
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    MigrationError(#[from] MigrationError),
+
+    #[error("Task join error: {0}")]
+    TaskJoinError(#[from] tokio::task::JoinError),
+
+    #[error("{0} doesn't match the checksum in its manifest")]
+    ChecksumMismatch(String),
+}
+
+/**
+Which rows an export covers.
+
+Items are selected by id and creation time, and their `kids` rows follow them. Users only
+have a creation time, so the id bounds don't apply to them.
+*/
+#[derive(Clone, Debug, Default)]
+pub struct ExportFilter {
+    pub start: Option<i64>,
+    pub end: Option<i64>,
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+}
+
+impl ExportFilter {
+    /**
+    Item ids to scan: `start..=end`, narrowed to the ids created between `after` and `before`.
+    `None` if no stored item can match.
+
+    HN ids increase with time, so each time bound becomes the first item created at or after it.
+    The few items whose ids are out of order near a bound may be left out.
+    */
+    pub async fn id_range(
+        &self,
+        storage: &dyn Storage,
+    ) -> Result<Option<RangeInclusive<i64>>, StorageError> {
+        let Some(max_id) = storage.max_id(None).await? else {
+            return Ok(None);
+        };
+        let mut start = self.start.unwrap_or(1).max(1);
+        let mut end = self.end.unwrap_or(max_id).min(max_id);
+        if let Some(after) = self.after {
+            match first_created_since(storage, after).await? {
+                Some(id) => start = start.max(id),
+                None => return Ok(None),
+            }
+        }
+        if let Some(before) = self.before {
+            if let Some(id) = first_created_since(storage, before).await? {
+                end = end.min(id - 1);
+            }
+        }
+        Ok((start <= end).then_some(start..=end))
+    }
+
+    /// Whether a row created at `time` (unix seconds) is within `after` and `before`
+    pub fn includes_time(&self, time: Option<i64>) -> bool {
+        if self.after.is_none() && self.before.is_none() {
+            return true;
+        }
+        let Some(time) = time else {
+            return false;
+        };
+        self.after.map_or(true, |after| time >= after.timestamp())
+            && self.before.map_or(true, |before| time < before.timestamp())
+    }
+}
+
+/// Id of the first item created at or after `at`
+async fn first_created_since(
+    storage: &dyn Storage,
+    at: DateTime<Utc>,
+) -> Result<Option<i64>, StorageError> {
+    // Nothing is created in the future, so this bounds the scan
+    let until = Utc::now() + Duration::days(1);
+    if at >= until {
+        return Ok(None);
+    }
+    Ok(storage
+        .items_in_time_range(at..until, None, Some(1))
+        .await?
+        .first()
+        .map(|item| item.id))
+}
+
+/// Writes `manifest` as pretty JSON to `out_dir/name`, replacing any previous version at once
+fn write_manifest<T: Serialize>(
+    out_dir: &Path,
+    name: &str,
+    manifest: &T,
+) -> Result<(), ExportError> {
+    let tmp_path = out_dir.join(format!("{}.tmp", name));
+    fs::write(&tmp_path, serde_json::to_vec_pretty(manifest)?)?;
+    fs::rename(&tmp_path, out_dir.join(name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ExportFilter;
+    use chrono::{TimeZone, Utc};
+
+    fn filter(after: Option<i64>, before: Option<i64>) -> ExportFilter {
+        ExportFilter {
+            after: after.map(|secs| Utc.timestamp_opt(secs, 0).unwrap()),
+            before: before.map(|secs| Utc.timestamp_opt(secs, 0).unwrap()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn includes_time_without_bounds_keeps_everything() {
+        let filter = filter(None, None);
+        assert!(filter.includes_time(Some(1_700_000_000)));
+        assert!(filter.includes_time(None));
+    }
+
+    #[test]
+    fn includes_time_is_inclusive_after_and_exclusive_before() {
+        let filter = filter(Some(100), Some(200));
+        assert!(!filter.includes_time(Some(99)));
+        assert!(filter.includes_time(Some(100)));
+        assert!(filter.includes_time(Some(199)));
+        assert!(!filter.includes_time(Some(200)));
+    }
+
+    #[test]
+    fn includes_time_with_one_bound() {
+        assert!(filter(Some(100), None).includes_time(Some(i64::MAX)));
+        assert!(!filter(None, Some(100)).includes_time(Some(100)));
+    }
+
+    #[test]
+    fn includes_time_drops_rows_without_a_time_once_bounded() {
+        assert!(!filter(Some(100), None).includes_time(None));
+        assert!(!filter(None, Some(100)).includes_time(None));
+    }
+}
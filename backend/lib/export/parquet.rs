@@ -0,0 +1,300 @@
+use arrow_array::{
+    ArrayRef, BooleanArray, Int32Array, Int64Array, RecordBatch, StringArray, TimestampSecondArray,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use log::info;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde::Serialize;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::{ExportError, ExportFilter};
+use crate::db::models::{Item, Kid, User};
+use crate::db::partitions::{self, PARTITION_SIZE};
+use crate::storage::Storage;
+
+/// Rows fetched per query
+const PAGE_SIZE: i64 = 10_000;
+
+/// Bounds the rows buffered in memory before a row group is compressed and written
+const ROW_GROUP_SIZE: usize = 100_000;
+
+/// Users aren't numbered, so their files are split by row count instead of id
+const USERS_PER_FILE: usize = 1_000_000;
+
+/// What `export` wrote. Paths are relative to the output directory
+#[derive(Serialize, Debug, Default)]
+pub struct ParquetSummary {
+    pub items: usize,
+    pub kids: usize,
+    pub users: usize,
+    pub files: Vec<String>,
+}
+
+/// A Parquet file written under a temporary name, and renamed into place once complete
+struct PartFile {
+    writer: ArrowWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    name: String,
+}
+
+impl PartFile {
+    fn create(
+        out_dir: &Path,
+        name: String,
+        schema: &SchemaRef,
+        props: &WriterProperties,
+    ) -> Result<Self, ExportError> {
+        let path = out_dir.join(&name);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("parquet.tmp");
+        let writer = ArrowWriter::try_new(
+            File::create(&tmp_path)?,
+            schema.clone(),
+            Some(props.clone()),
+        )?;
+        Ok(Self {
+            writer,
+            tmp_path,
+            path,
+            name,
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ExportError> {
+        Ok(self.writer.write(batch)?)
+    }
+
+    /// Flushes the last row group and moves the file into place, returning its name
+    fn finish(self) -> Result<String, ExportError> {
+        self.writer.close()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(self.name)
+    }
+}
+
+fn timestamp() -> DataType {
+    DataType::Timestamp(TimeUnit::Second, Some("UTC".into()))
+}
+
+fn items_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("deleted", DataType::Boolean, true),
+        Field::new("type", DataType::Utf8, true),
+        Field::new("by", DataType::Utf8, true),
+        Field::new("time", timestamp(), true),
+        Field::new("text", DataType::Utf8, true),
+        Field::new("dead", DataType::Boolean, true),
+        Field::new("parent", DataType::Int64, true),
+        Field::new("poll", DataType::Int64, true),
+        Field::new("url", DataType::Utf8, true),
+        Field::new("score", DataType::Int64, true),
+        Field::new("title", DataType::Utf8, true),
+        Field::new("parts", DataType::Utf8, true),
+        Field::new("descendants", DataType::Int64, true),
+        Field::new("story_id", DataType::Int64, true),
+        Field::new("depth", DataType::Int32, true),
+    ]))
+}
+
+fn kids_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("item", DataType::Int64, false),
+        Field::new("kid", DataType::Int64, false),
+        Field::new("display_order", DataType::Int64, true),
+    ]))
+}
+
+fn users_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Utf8, false),
+        Field::new("created", timestamp(), true),
+        Field::new("karma", DataType::Int64, true),
+        Field::new("about", DataType::Utf8, true),
+        Field::new("submitted", DataType::Utf8, true),
+    ]))
+}
+
+fn items_batch(schema: &SchemaRef, items: &[Item]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(items.iter().map(|i| i.id))),
+        Arc::new(BooleanArray::from_iter(items.iter().map(|i| i.deleted))),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.type_.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.by.as_deref()),
+        )),
+        Arc::new(
+            TimestampSecondArray::from_iter(items.iter().map(|i| i.time)).with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.text.as_deref()),
+        )),
+        Arc::new(BooleanArray::from_iter(items.iter().map(|i| i.dead))),
+        Arc::new(Int64Array::from_iter(items.iter().map(|i| i.parent))),
+        Arc::new(Int64Array::from_iter(items.iter().map(|i| i.poll))),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.url.as_deref()),
+        )),
+        Arc::new(Int64Array::from_iter(items.iter().map(|i| i.score))),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.title.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter(
+            items.iter().map(|i| i.parts.as_deref()),
+        )),
+        Arc::new(Int64Array::from_iter(items.iter().map(|i| i.descendants))),
+        Arc::new(Int64Array::from_iter(items.iter().map(|i| i.story_id))),
+        Arc::new(Int32Array::from_iter(items.iter().map(|i| i.depth))),
+    ];
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+fn kids_batch(schema: &SchemaRef, kids: &[Kid]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(kids.iter().map(|k| k.item))),
+        Arc::new(Int64Array::from_iter_values(kids.iter().map(|k| k.kid))),
+        Arc::new(Int64Array::from_iter(kids.iter().map(|k| k.display_order))),
+    ];
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+fn users_batch(schema: &SchemaRef, users: &[User]) -> Result<RecordBatch, ArrowError> {
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(users.iter().map(|u| &u.id))),
+        Arc::new(
+            TimestampSecondArray::from_iter(users.iter().map(|u| u.created)).with_timezone("UTC"),
+        ),
+        Arc::new(Int64Array::from_iter(users.iter().map(|u| u.karma))),
+        Arc::new(StringArray::from_iter(
+            users.iter().map(|u| u.about.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter(
+            users.iter().map(|u| u.submitted.as_deref()),
+        )),
+    ];
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+/**
+Streams `items`, `kids` and `users` into zstd-compressed Parquet files under `out_dir`.
+
+Items and kids are split along the DB partitions, e.g. `items/items_p0042.parquet` and
+`kids/kids_p0042.parquet`, so a reader can skip whole id ranges. `kids` rows are those of the
+exported items. Users go to `users/users_0000.parquet` and onwards. Partitions with no matching
+items get no file, and files only appear under their final name once complete.
+*/
+pub async fn export(
+    storage: &dyn Storage,
+    out_dir: &Path,
+    filter: &ExportFilter,
+    zstd_level: i32,
+) -> Result<ParquetSummary, ExportError> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::try_new(zstd_level)?))
+        .set_max_row_group_size(ROW_GROUP_SIZE)
+        .build();
+    let (items_schema, kids_schema, users_schema) = (items_schema(), kids_schema(), users_schema());
+    let mut summary = ParquetSummary::default();
+
+    if let Some(ids) = filter.id_range(storage).await? {
+        for index in *ids.start() / PARTITION_SIZE..=*ids.end() / PARTITION_SIZE {
+            let partition = partitions::id_range(index);
+            let end = (*partition.end()).min(*ids.end());
+            let mut cursor = (*partition.start()).max(*ids.start());
+            let mut files: Option<(PartFile, PartFile)> = None;
+            while cursor <= end {
+                let page = storage.scan_items(cursor..=end, PAGE_SIZE).await?;
+                let Some(last) = page.last() else {
+                    break;
+                };
+                cursor = last.id + 1;
+                let page: Vec<Item> = page
+                    .into_iter()
+                    .filter(|item| filter.includes_time(item.time))
+                    .collect();
+                if page.is_empty() {
+                    continue;
+                }
+                let item_ids: Vec<i64> = page.iter().map(|item| item.id).collect();
+                let kids = storage.get_kids(&item_ids).await?;
+
+                if files.is_none() {
+                    files = Some((
+                        PartFile::create(
+                            out_dir,
+                            format!("items/items_p{:04}.parquet", index),
+                            &items_schema,
+                            &props,
+                        )?,
+                        PartFile::create(
+                            out_dir,
+                            format!("kids/kids_p{:04}.parquet", index),
+                            &kids_schema,
+                            &props,
+                        )?,
+                    ));
+                }
+                if let Some((items_file, kids_file)) = files.as_mut() {
+                    items_file.write(&items_batch(&items_schema, &page)?)?;
+                    kids_file.write(&kids_batch(&kids_schema, &kids)?)?;
+                }
+                summary.items += page.len();
+                summary.kids += kids.len();
+            }
+            if let Some((items_file, kids_file)) = files {
+                summary.files.push(items_file.finish()?);
+                summary.files.push(kids_file.finish()?);
+                info!(
+                    "Exported partition {}: {} items so far",
+                    index, summary.items
+                );
+            }
+        }
+    }
+
+    let mut after: Option<String> = None;
+    let mut users_file: Option<PartFile> = None;
+    let mut in_file = 0;
+    loop {
+        let page = storage.scan_users(after.as_deref(), PAGE_SIZE).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.id.clone());
+        let page: Vec<User> = page
+            .into_iter()
+            .filter(|user| filter.includes_time(user.created))
+            .collect();
+        if page.is_empty() {
+            continue;
+        }
+        if in_file >= USERS_PER_FILE {
+            if let Some(file) = users_file.take() {
+                summary.files.push(file.finish()?);
+            }
+            in_file = 0;
+        }
+        if users_file.is_none() {
+            let name = format!("users/users_{:04}.parquet", summary.users / USERS_PER_FILE);
+            users_file = Some(PartFile::create(out_dir, name, &users_schema, &props)?);
+        }
+        if let Some(file) = users_file.as_mut() {
+            file.write(&users_batch(&users_schema, &page)?)?;
+        }
+        summary.users += page.len();
+        in_file += page.len();
+    }
+    if let Some(file) = users_file {
+        summary.files.push(file.finish()?);
+    }
+    Ok(summary)
+}
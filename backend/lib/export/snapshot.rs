@@ -0,0 +1,380 @@
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use diesel::connection::SimpleConnection;
+use diesel::{Connection, SqliteConnection};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use super::{write_manifest, ExportError};
+use crate::db::migrations;
+use crate::db::models::{Item, Kid, User};
+use crate::storage::{SqliteStorage, Storage, StorageError};
+
+/// Rows fetched per query
+const PAGE_SIZE: i64 = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SnapshotFormat {
+    /// One SQLite file with the mirror schema, ready to query once decompressed
+    Sqlite,
+    /// One JSON object per line, a file per table
+    Jsonl,
+}
+
+impl fmt::Display for SnapshotFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotFormat::Sqlite => write!(f, "sqlite"),
+            SnapshotFormat::Jsonl => write!(f, "jsonl"),
+        }
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(SnapshotFormat::Sqlite),
+            "jsonl" => Ok(SnapshotFormat::Jsonl),
+            _ => Err(format!(
+                "unknown snapshot format {:?}, expected sqlite or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RowCounts {
+    pub items: usize,
+    pub kids: usize,
+    pub users: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct IdRange {
+    pub min: i64,
+    pub max: i64,
+}
+
+/// A compressed file of the snapshot, as published
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SnapshotFile {
+    pub name: String,
+    pub bytes: u64,
+    pub sha256: String,
+}
+
+/// Written next to the snapshot files as `hn-<format>-<date>.manifest.json`
+#[derive(Serialize, Debug)]
+pub struct Manifest {
+    pub date: NaiveDate,
+    pub format: SnapshotFormat,
+    pub rows: RowCounts,
+    /// Lowest and highest item id in the snapshot, `None` if it has no items
+    pub id_range: Option<IdRange>,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// Passes writes through to a file, hashing and counting them on the way
+struct HashingWriter {
+    inner: BufWriter<File>,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A zstd file written under a temporary name, and renamed into place once complete
+pub(super) struct CompressedFile {
+    encoder: zstd::Encoder<'static, HashingWriter>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+    name: String,
+}
+
+impl CompressedFile {
+    pub(super) fn create(
+        out_dir: &Path,
+        name: String,
+        zstd_level: i32,
+    ) -> Result<Self, ExportError> {
+        let path = out_dir.join(&name);
+        let tmp_path = out_dir.join(format!("{}.tmp", name));
+        let writer = HashingWriter {
+            inner: BufWriter::new(File::create(&tmp_path)?),
+            hasher: Sha256::new(),
+            bytes: 0,
+        };
+        Ok(Self {
+            encoder: zstd::Encoder::new(writer, zstd_level)?,
+            tmp_path,
+            path,
+            name,
+        })
+    }
+
+    pub(super) fn write_line<T: Serialize>(&mut self, row: &T) -> Result<(), ExportError> {
+        serde_json::to_writer(&mut self.encoder, row)?;
+        self.encoder.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Ends the zstd frame and moves the file into place
+    pub(super) fn finish(self) -> Result<SnapshotFile, ExportError> {
+        let mut writer = self.encoder.finish()?;
+        writer.flush()?;
+        writer.inner.get_ref().sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(SnapshotFile {
+            name: self.name,
+            bytes: writer.bytes,
+            sha256: format!("{:x}", writer.hasher.finalize()),
+        })
+    }
+}
+
+/// Where a snapshot's rows go as they're read
+#[async_trait]
+trait SnapshotSink: Send {
+    async fn items(&mut self, items: &[Item], kids: &[Kid]) -> Result<(), ExportError>;
+
+    async fn users(&mut self, users: &[User]) -> Result<(), ExportError>;
+
+    /// Writes out whatever is buffered, returning the published files
+    async fn finish(self) -> Result<Vec<SnapshotFile>, ExportError>;
+}
+
+struct JsonlSink {
+    items: CompressedFile,
+    kids: CompressedFile,
+    users: CompressedFile,
+}
+
+#[async_trait]
+impl SnapshotSink for JsonlSink {
+    async fn items(&mut self, items: &[Item], kids: &[Kid]) -> Result<(), ExportError> {
+        for item in items {
+            self.items.write_line(item)?;
+        }
+        for kid in kids {
+            self.kids.write_line(kid)?;
+        }
+        Ok(())
+    }
+
+    async fn users(&mut self, users: &[User]) -> Result<(), ExportError> {
+        for user in users {
+            self.users.write_line(user)?;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<Vec<SnapshotFile>, ExportError> {
+        Ok(vec![
+            self.items.finish()?,
+            self.kids.finish()?,
+            self.users.finish()?,
+        ])
+    }
+}
+
+/// Fills a fresh mirror through `SqliteStorage`, so `story_id`, `depth` and `path` are resolved as on ingest
+struct SqliteSink {
+    storage: SqliteStorage,
+    db_path: PathBuf,
+    out_dir: PathBuf,
+    name: String,
+    zstd_level: i32,
+}
+
+impl SqliteSink {
+    async fn create(out_dir: &Path, stem: &str, zstd_level: i32) -> Result<Self, ExportError> {
+        let db_path = out_dir.join(format!("{}.db", stem));
+        // Left over from an interrupted run
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = db_path.clone().into_os_string();
+            path.push(suffix);
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        let db_url = format!("sqlite://{}", db_path.display());
+        migrations::run_pending(&db_url).await?;
+        Ok(Self {
+            storage: SqliteStorage::open(&db_url)?,
+            db_path,
+            out_dir: out_dir.to_path_buf(),
+            name: format!("{}.db.zst", stem),
+            zstd_level,
+        })
+    }
+}
+
+#[async_trait]
+impl SnapshotSink for SqliteSink {
+    async fn items(&mut self, items: &[Item], kids: &[Kid]) -> Result<(), ExportError> {
+        self.storage.upsert_items(items, kids).await?;
+        Ok(())
+    }
+
+    async fn users(&mut self, users: &[User]) -> Result<(), ExportError> {
+        for user in users {
+            self.storage.upsert_user(user).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(self) -> Result<Vec<SnapshotFile>, ExportError> {
+        // Closing the mirror's connection lets the compaction below take it over
+        drop(self.storage);
+        let db_path = self.db_path;
+        tokio::task::spawn_blocking(move || -> Result<SnapshotFile, ExportError> {
+            let mut conn = SqliteConnection::establish(&db_path.to_string_lossy())
+                .map_err(StorageError::from)?;
+            // Filling the mirror went through the sync bookkeeping, which isn't part of the
+            // dataset. The tables stay, so the mirror can still be synced with `backend`
+            conn.batch_execute(
+                "DELETE FROM changed_items; DELETE FROM changed_users; DELETE FROM pending_updates;",
+            )
+            .map_err(StorageError::from)?;
+            // Fold the WAL back in, so the file stands alone
+            conn.batch_execute("PRAGMA journal_mode = DELETE; VACUUM;")
+                .map_err(StorageError::from)?;
+            drop(conn);
+
+            let mut file = CompressedFile::create(&self.out_dir, self.name, self.zstd_level)?;
+            io::copy(&mut File::open(&db_path)?, &mut file.encoder)?;
+            let file = file.finish()?;
+            fs::remove_file(&db_path)?;
+            Ok(file)
+        })
+        .await?
+        .map(|file| vec![file])
+    }
+}
+
+/**
+Dumps every item, kid and user into a dated, zstd-compressed snapshot under `out_dir`, and writes
+its manifest last.
+
+Files are named like the published ones, e.g. `hn-sqlite-20230429.db.zst`, or
+`hn-items-20230429.jsonl.zst` with its `kids` and `users` counterparts. Rows are read in id order,
+so an unchanged database gives the same JSONL files. Items are read up to the highest id at the start,
+so ingestion can keep running.
+
+Pages are read by separate queries, not in one transaction, so a snapshot taken while ingestion runs
+isn't a point-in-time view: rows changed mid-run may appear in either version, and an item's `kids`
+may be from a moment later than the item. The delta for the same day covers those changes.
+*/
+pub async fn snapshot(
+    storage: &dyn Storage,
+    out_dir: &Path,
+    format: SnapshotFormat,
+    date: NaiveDate,
+    zstd_level: i32,
+) -> Result<Manifest, ExportError> {
+    fs::create_dir_all(out_dir)?;
+    let date_stamp = date.format("%Y%m%d");
+    match format {
+        SnapshotFormat::Sqlite => {
+            let sink =
+                SqliteSink::create(out_dir, &format!("hn-sqlite-{}", date_stamp), zstd_level)
+                    .await?;
+            write_snapshot(storage, out_dir, format, date, sink).await
+        }
+        SnapshotFormat::Jsonl => {
+            let file = |table: &str| {
+                CompressedFile::create(
+                    out_dir,
+                    format!("hn-{}-{}.jsonl.zst", table, date_stamp),
+                    zstd_level,
+                )
+            };
+            let sink = JsonlSink {
+                items: file("items")?,
+                kids: file("kids")?,
+                users: file("users")?,
+            };
+            write_snapshot(storage, out_dir, format, date, sink).await
+        }
+    }
+}
+
+async fn write_snapshot(
+    storage: &dyn Storage,
+    out_dir: &Path,
+    format: SnapshotFormat,
+    date: NaiveDate,
+    mut sink: impl SnapshotSink,
+) -> Result<Manifest, ExportError> {
+    let mut rows = RowCounts::default();
+    let mut id_range: Option<IdRange> = None;
+
+    if let Some(max_id) = storage.max_id(None).await? {
+        let mut cursor = 1;
+        while cursor <= max_id {
+            let page = storage.scan_items(cursor..=max_id, PAGE_SIZE).await?;
+            let (Some(first), Some(last)) = (page.first(), page.last()) else {
+                break;
+            };
+            cursor = last.id + 1;
+            id_range = Some(IdRange {
+                min: id_range.map_or(first.id, |range| range.min),
+                max: last.id,
+            });
+
+            let item_ids: Vec<i64> = page.iter().map(|item| item.id).collect();
+            let mut kids = storage.get_kids(&item_ids).await?;
+            kids.sort_by_key(|kid| (kid.item, kid.kid));
+            sink.items(&page, &kids).await?;
+            rows.items += page.len();
+            rows.kids += kids.len();
+            if rows.items % (PAGE_SIZE as usize * 100) < page.len() {
+                info!("Snapshot at item {}: {} items so far", last.id, rows.items);
+            }
+        }
+    }
+
+    let mut after: Option<String> = None;
+    loop {
+        let page = storage.scan_users(after.as_deref(), PAGE_SIZE).await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after = Some(last.id.clone());
+        sink.users(&page).await?;
+        rows.users += page.len();
+    }
+
+    let manifest = Manifest {
+        date,
+        format,
+        rows,
+        id_range,
+        files: sink.finish().await?,
+    };
+    let name = format!("hn-{}-{}.manifest.json", format, date.format("%Y%m%d"));
+    write_manifest(out_dir, &name, &manifest)?;
+    Ok(manifest)
+}
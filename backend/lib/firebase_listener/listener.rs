@@ -18,6 +18,7 @@ pub struct FirebaseListener {
 pub struct Item {
     pub id: i64,
     pub deleted: Option<bool>,
+    #[serde(rename = "type")]
     pub type_: Option<String>,
     pub by: Option<String>,
     pub time: Option<i64>,
@@ -58,8 +59,11 @@ pub struct User {
 pub enum FirebaseListenerErr {
     ConnectError(String),
     ParseError(String),
+    /// HN answered `null` for this item id
+    EmptyItem(i64),
     JsonParseError(#[from] serde_json::Error), // Added for JSON parsing errors
     ChannelError(#[from] SendError<i64>),
+    ProfileChannelError(#[from] SendError<String>),
     RequestError(#[from] reqwest::Error),
 }
 
@@ -68,8 +72,10 @@ impl fmt::Display for FirebaseListenerErr {
         match self {
             FirebaseListenerErr::ConnectError(e) => write!(f, "ConnectError: {}", e),
             FirebaseListenerErr::ParseError(e) => write!(f, "ParseError: {}", e),
+            FirebaseListenerErr::EmptyItem(id) => write!(f, "EmptyItem: item {} is null", id),
             FirebaseListenerErr::JsonParseError(e) => write!(f, "ParseError: {}", e),
             FirebaseListenerErr::ChannelError(e) => write!(f, "ChannelError: {}", e),
+            FirebaseListenerErr::ProfileChannelError(e) => write!(f, "ChannelError: {}", e),
             FirebaseListenerErr::RequestError(e) => write!(f, "RequestError: {}", e),
         }
     }
@@ -97,9 +103,31 @@ impl FirebaseListener {
         }
 
         response
-            .json::<Item>()
+            .json::<Option<Item>>()
             .await
-            .map_err(|_| FirebaseListenerErr::ParseError(format!("Item {} is not valid!", item_id)))
+            .map_err(|_| {
+                FirebaseListenerErr::ParseError(format!("Item {} is not valid!", item_id))
+            })?
+            .ok_or(FirebaseListenerErr::EmptyItem(item_id))
+    }
+
+    /// `None` if HN answers `null`, e.g. for a deleted account
+    pub async fn get_user(&self, user_id: &str) -> Result<Option<User>, FirebaseListenerErr> {
+        let url = format!("{}/user/{}.json", self.base_url, user_id);
+        let response = self.client.get(&url).send().await?;
+
+        if !response.status().is_success() {
+            return Err(FirebaseListenerErr::ConnectError(format!(
+                "Received unexpected status code for user {}: {}",
+                user_id,
+                response.status()
+            )));
+        }
+
+        response
+            .json::<Option<User>>()
+            .await
+            .map_err(|_| FirebaseListenerErr::ParseError(format!("User {} is not valid!", user_id)))
     }
 
     pub async fn get_max_id(&self) -> Result<i64, FirebaseListenerErr> {
@@ -119,9 +147,11 @@ impl FirebaseListener {
         Ok(max_id)
     }
 
+    /// Sends updated item ids on `tx`, and the ids of updated profiles on `profiles_tx`
     pub async fn listen_to_updates(
         &self,
         tx: Sender<i64>,
+        profiles_tx: Sender<String>,
         cancel_token: CancellationToken,
     ) -> Result<(), FirebaseListenerErr> {
         let url = format!("{}/updates.json", self.base_url);
@@ -145,6 +175,12 @@ impl FirebaseListener {
                                                 tx.send_async(id).await?;
                                             }
                                         }
+                                        if let Some(profiles) = update.data.profiles {
+                                            debug!("{:?} updated profiles", profiles.len());
+                                            for profile in profiles {
+                                                profiles_tx.send_async(profile).await?;
+                                            }
+                                        }
                                     }
                                     Err(err) => {
                                         if ev.event_type == "keep-alive" {
@@ -5,25 +5,50 @@ use crate::triton::{
     self, grpc_inference_service_client::GrpcInferenceServiceClient,
     model_infer_request::InferInputTensor,
 };
-use ndarray::Array2;
-use tokenizers::tokenizer::Tokenizer;
-//use tokio::sync::Semaphore;
+use ndarray::{s, Array2, Array3};
+use std::ops::Range;
+use tokenizers::tokenizer::{Encoding, PaddingParams, Tokenizer, TruncationParams};
 use tokio::task::spawn_blocking;
 use tonic::{transport::Channel, Request, Status};
 
+/// Width of e5-small-v2's hidden state, and so of its embeddings
+pub const EMBEDDING_DIM: usize = 384;
+
+/// e5 is trained with these prefixes marking what each side of a search is
+pub(crate) const PASSAGE_PREFIX: &str = "passage: ";
+pub(crate) const QUERY_PREFIX: &str = "query: ";
+
 pub struct E5Embedder {
     client: GrpcInferenceServiceClient<Channel>,
-    /// Caps concurrent requests from client
-    /// Max length of document to be embedded
+    /// Max tokens per text, special tokens included. Longer ones are truncated
     max_seq_length: usize,
+    /// Tokens taken up by `PASSAGE_PREFIX`
+    passage_prefix_tokens: usize,
+    /// Tokens the model's template adds around each text, `[CLS]` and `[SEP]`
+    special_tokens: usize,
+    /// Plain tokenizer, for counting and splitting texts
     tokenizer: Tokenizer,
+    /// Truncates to `max_seq_length` and pads batches, for what's sent to the model
+    model_tokenizer: Tokenizer,
+}
+
+/// A window of a longer text, as split by `E5Embedder::chunks`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk<'a> {
+    pub text: &'a str,
+    /// Tokens of the whole text that the chunk covers
+    pub tokens: Range<usize>,
+    /// Bytes of the whole text that the chunk covers, so `text == &whole[bytes]`
+    pub bytes: Range<usize>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum E5Error {
     ConnectError(String),
     TokenizerError(String),
     ParsingError(String),
+    /// The `EmbedQueue` worker is gone, so nothing will answer
+    QueueClosed,
 }
 
 impl From<tonic::transport::Error> for E5Error {
@@ -44,46 +69,77 @@ impl From<Box<dyn std::error::Error + Send + Sync>> for E5Error {
     }
 }
 
+/**
+Splits Triton's output, one `[batch_size, seq_len, embed_depth]` tensor of little-endian f32s,
+into a `[len, embed_depth]` array per input, cut to `lens`, the unpadded length of each input.
+*/
 fn parse_raw_output(
     data: Vec<Vec<u8>>,
-    batch_size: usize,
-    channel: usize,
+    lens: &[usize],
+    seq_len: usize,
     embed_depth: usize,
 ) -> Result<Vec<Array2<f32>>, E5Error> {
-    if data.len() != batch_size {
+    let [raw] = <[Vec<u8>; 1]>::try_from(data)
+        .map_err(|_| E5Error::ParsingError("Expected a single output tensor".into()))?;
+    if raw.len() != lens.len() * seq_len * embed_depth * 4 {
         return Err(E5Error::ParsingError(
-            "Output number of tensors does not match input!".into(),
+            "Output size does not match the input batch".into(),
         ));
     }
 
-    // Check that all inputs are f32
-    if data.iter().any(|v| v.len() % 4 != 0) {}
-
-    let mut output = Vec::new();
-    for chunk in data {
-        // Convert the data bytes into f32s
-        let len = chunk.len() / 4;
-        if len % 4 != 0 {
-            return Err(E5Error::ParsingError(
-                "Raw data length is not a multiple of 4".into(),
-            ));
-        }
+    // Convert the data bytes into f32s
+    let values: Vec<f32> = raw
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    let batch = Array3::from_shape_vec((lens.len(), seq_len, embed_depth), values)
+        .map_err(|_| E5Error::ParsingError("Embedding data could not match shape".into()))?;
+    Ok(batch
+        .outer_iter()
+        .zip(lens)
+        .map(|(sequence, len)| sequence.slice(s![..*len, ..]).to_owned())
+        .collect())
+}
 
-        let mut batch = Vec::with_capacity(len);
-        for i in (0..chunk.len()).step_by(4) {
-            let value = f32::from_le_bytes(
-                chunk[i..i + 4]
-                    .try_into()
-                    .map_err(|_| E5Error::ParsingError("Raw data float size mismatch".into()))?,
-            );
-            batch.push(value);
+/**
+Token and byte spans of the windows `E5Embedder::chunks` cuts, given each token's byte `offsets`.
+Needs `max_tokens > overlap`, or it would never advance.
+*/
+fn token_windows(
+    offsets: &[(usize, usize)],
+    max_tokens: usize,
+    overlap: usize,
+) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut windows = Vec::new();
+    let mut start = 0;
+    while start < offsets.len() {
+        let end = (start + max_tokens).min(offsets.len());
+        windows.push((start..end, offsets[start].0..offsets[end - 1].1));
+        if end == offsets.len() {
+            break;
         }
-        let batch_array = Array2::from_shape_vec((channel, embed_depth), batch)
-            .map_err(|_| E5Error::ParsingError("Embedding data could not match shape".into()))?;
-        output.push(batch_array);
+        start = end - overlap;
     }
+    windows
+}
 
-    Ok(output)
+/// One `[batch, seq_len]` INT64 input of the e5-small-v2 model
+fn input_tensor(
+    name: &str,
+    values: Vec<i64>,
+    batch_size: usize,
+    seq_len: usize,
+) -> InferInputTensor {
+    InferInputTensor {
+        name: name.into(),
+        datatype: "INT64".into(),
+        contents: Some(triton::InferTensorContents {
+            int64_contents: values,
+            ..Default::default()
+        }),
+        shape: vec![batch_size as i64, seq_len as i64],
+        ..Default::default()
+    }
 }
 
 impl E5Embedder {
@@ -96,73 +152,212 @@ impl E5Embedder {
         let tokenizer = spawn_blocking(|| Tokenizer::from_pretrained("intfloat/e5-small-v2", None))
             .await
             .unwrap()?;
-        //    let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+        let max_seq_length = 512;
+        let passage_prefix_tokens = tokenizer.encode(PASSAGE_PREFIX, false)?.len();
+        let special_tokens = tokenizer.encode("", true)?.len();
+        let mut model_tokenizer = tokenizer.clone();
+        // Truncation makes room for the special tokens, so `[SEP]` always ends the sequence
+        model_tokenizer
+            .with_truncation(Some(TruncationParams {
+                max_length: max_seq_length,
+                ..Default::default()
+            }))?
+            .with_padding(Some(PaddingParams::default()));
         Ok(Self {
             client,
-            max_seq_length: 512,
+            max_seq_length,
+            passage_prefix_tokens,
+            special_tokens,
             tokenizer,
+            model_tokenizer,
         })
     }
 
-    /// API will be changed, just a dummy to get comms w/ triton working
-    pub async fn encode(&self, txt: &str) -> Result<Vec<Array2<f32>>, E5Error> {
-        if txt.len() > self.max_seq_length {
-            return Err(E5Error::TokenizerError(
-                "Input sequence is too long!".into(),
-            ));
+    /// Most tokens of a passage `embed_passage` sees, once its prefix and special tokens are added.
+    /// The rest is truncated
+    pub fn max_passage_tokens(&self) -> usize {
+        self.max_seq_length - self.special_tokens - self.passage_prefix_tokens
+    }
+
+    /// Number of tokens `txt` is encoded to, without any prefix or special tokens
+    pub fn count_tokens(&self, txt: &str) -> Result<usize, E5Error> {
+        Ok(self.tokenizer.encode(txt, false)?.len())
+    }
+
+    /**
+    Splits `txt` into windows of at most `max_tokens` tokens, each starting `overlap` tokens before
+    the previous one ends, so no passage loses the context just before it.
+
+    Windows follow token boundaries, so a chunk may start or end inside a word split into several
+    tokens. A text that fits in `max_tokens` comes back as one chunk, and an empty one as none.
+    */
+    pub fn chunks<'a>(
+        &self,
+        txt: &'a str,
+        max_tokens: usize,
+        overlap: usize,
+    ) -> Result<Vec<Chunk<'a>>, E5Error> {
+        if max_tokens <= overlap {
+            return Err(E5Error::TokenizerError(format!(
+                "Chunks of {} tokens can't overlap by {}",
+                max_tokens, overlap
+            )));
         }
         let encoding = self.tokenizer.encode(txt, false)?;
+        Ok(token_windows(encoding.get_offsets(), max_tokens, overlap)
+            .into_iter()
+            .map(|(tokens, bytes)| Chunk {
+                text: &txt[bytes.clone()],
+                tokens,
+                bytes,
+            })
+            .collect())
+    }
+
+    /// Embedding of a document chunk, for storage
+    pub async fn embed_passage(&self, txt: &str) -> Result<Vec<f32>, E5Error> {
+        self.embed_one(&format!("{}{}", PASSAGE_PREFIX, txt)).await
+    }
+
+    /// Embedding of a search query, to compare against stored passages
+    pub async fn embed_query(&self, txt: &str) -> Result<Vec<f32>, E5Error> {
+        self.embed_one(&format!("{}{}", QUERY_PREFIX, txt)).await
+    }
+
+    /// Embeddings of document chunks in one request, in the same order
+    pub async fn embed_passages(&self, txts: &[&str]) -> Result<Vec<Vec<f32>>, E5Error> {
+        let txts: Vec<String> = txts
+            .iter()
+            .map(|txt| format!("{}{}", PASSAGE_PREFIX, txt))
+            .collect();
+        self.embed(&txts.iter().map(String::as_str).collect::<Vec<_>>())
+            .await
+    }
+
+    async fn embed_one(&self, txt: &str) -> Result<Vec<f32>, E5Error> {
+        self.embed(&[txt])
+            .await?
+            .pop()
+            .ok_or_else(|| E5Error::ParsingError("No output sequence".into()))
+    }
+
+    /// Mean of each text's token states, L2-normalized, as e5 is trained to be used.
+    /// Texts must already carry their prefix
+    pub(crate) async fn embed(&self, txts: &[&str]) -> Result<Vec<Vec<f32>>, E5Error> {
+        self.encode_batch(txts)
+            .await?
+            .into_iter()
+            .map(|tokens| {
+                let mean = tokens
+                    .mean_axis(ndarray::Axis(0))
+                    .ok_or_else(|| E5Error::ParsingError("Empty output sequence".into()))?;
+                let norm = mean.dot(&mean).sqrt();
+                Ok(mean.iter().map(|x| x / norm.max(f32::EPSILON)).collect())
+            })
+            .collect()
+    }
+
+    /// Token states of a single text, `[tokens, EMBEDDING_DIM]`
+    pub async fn encode(&self, txt: &str) -> Result<Array2<f32>, E5Error> {
+        self.encode_batch(&[txt])
+            .await?
+            .pop()
+            .ok_or_else(|| E5Error::ParsingError("No output sequence".into()))
+    }
+
+    /**
+    Token states of each text, `[tokens, EMBEDDING_DIM]`, from a single request.
+
+    Texts are wrapped in `[CLS]` and `[SEP]` as the model was trained on, and truncated to
+    `max_seq_length` tokens with those included. They're then padded on the right to the longest
+    one in the batch so they fit one tensor. The padding is cut off the output again.
+    */
+    pub async fn encode_batch(&self, txts: &[&str]) -> Result<Vec<Array2<f32>>, E5Error> {
+        if txts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let encodings = self.model_tokenizer.encode_batch(txts.to_vec(), true)?;
+        let batch_size = encodings.len();
+        let seq_len = encodings.first().map_or(0, |encoding| encoding.len());
+        let lens: Vec<usize> = encodings
+            .iter()
+            .map(|encoding| {
+                encoding
+                    .get_attention_mask()
+                    .iter()
+                    .filter(|x| **x == 1)
+                    .count()
+            })
+            .collect();
+        let flatten = |field: fn(&Encoding) -> &[u32]| -> Vec<i64> {
+            encodings
+                .iter()
+                .flat_map(|encoding| field(encoding).iter().map(|x| *x as i64))
+                .collect()
+        };
+
         let mut client = self.client.clone();
         // Refer to e5-small-v2 Triton config.pbtxt
         let request = Request::new(triton::ModelInferRequest {
             model_name: "e5-small-v2".into(),
             model_version: "1".into(),
             inputs: vec![
-                InferInputTensor {
-                    name: "token_type_ids".into(),
-                    datatype: "INT64".into(),
-                    contents: Some(triton::InferTensorContents {
-                        int64_contents: encoding.get_type_ids().iter().map(|x| *x as i64).collect(),
-                        ..Default::default()
-                    }),
-                    shape: vec![encoding.n_sequences() as i64, encoding.len() as i64],
-                    ..Default::default()
-                },
-                InferInputTensor {
-                    name: "attention_mask".into(),
-                    datatype: "INT64".into(),
-                    contents: Some(triton::InferTensorContents {
-                        int64_contents: encoding
-                            .get_attention_mask()
-                            .iter()
-                            .map(|x| *x as i64)
-                            .collect(),
-                        ..Default::default()
-                    }),
-                    shape: vec![encoding.n_sequences() as i64, encoding.len() as i64],
-                    ..Default::default()
-                },
-                InferInputTensor {
-                    name: "input_ids".into(),
-                    datatype: "INT64".into(),
-                    contents: Some(triton::InferTensorContents {
-                        int64_contents: encoding.get_ids().iter().map(|x| *x as i64).collect(),
-                        ..Default::default()
-                    }),
-                    shape: vec![encoding.n_sequences() as i64, encoding.len() as i64],
-                    ..Default::default()
-                },
+                input_tensor(
+                    "token_type_ids",
+                    flatten(Encoding::get_type_ids),
+                    batch_size,
+                    seq_len,
+                ),
+                input_tensor(
+                    "attention_mask",
+                    flatten(Encoding::get_attention_mask),
+                    batch_size,
+                    seq_len,
+                ),
+                input_tensor("input_ids", flatten(Encoding::get_ids), batch_size, seq_len),
             ],
             ..Default::default()
         });
         let response = client.model_infer(request).await?.into_inner();
-        //let outputs = response.outputs;
-        let raw_outputs = response.raw_output_contents;
-        println!("{:?}", raw_outputs.len());
+        parse_raw_output(response.raw_output_contents, &lens, seq_len, EMBEDDING_DIM)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::token_windows;
+
+    /// Offsets of `n` three-letter words separated by single spaces, like "abc abc abc"
+    fn words(n: usize) -> Vec<(usize, usize)> {
+        (0..n).map(|i| (i * 4, i * 4 + 3)).collect()
+    }
 
-        let embeddings =
-            parse_raw_output(raw_outputs, encoding.n_sequences(), encoding.len(), 384)?;
+    #[test]
+    fn token_windows_of_a_short_text_is_the_whole_text() {
+        assert_eq!(token_windows(&words(3), 10, 2), vec![(0..3, 0..11)]);
+    }
+
+    #[test]
+    fn token_windows_of_an_empty_text_is_empty() {
+        assert!(token_windows(&[], 10, 2).is_empty());
+    }
+
+    #[test]
+    fn token_windows_overlap_and_end_on_the_last_token() {
+        let windows = token_windows(&words(10), 4, 1);
+        let tokens: Vec<_> = windows.iter().map(|(tokens, _)| tokens.clone()).collect();
+        assert_eq!(tokens, vec![0..4, 3..7, 6..10]);
+        // Byte spans run from the first token's start to the last token's end
+        assert_eq!(windows[1].1, 12..27);
+        assert_eq!(windows[2].1, 24..39);
+    }
 
-        Ok(embeddings)
+    #[test]
+    fn token_windows_without_overlap_tile_the_text() {
+        let tokens: Vec<_> = token_windows(&words(9), 3, 0)
+            .into_iter()
+            .map(|(tokens, _)| tokens)
+            .collect();
+        assert_eq!(tokens, vec![0..3, 3..6, 6..9]);
     }
 }
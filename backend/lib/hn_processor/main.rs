@@ -1,23 +1,228 @@
-use crate::config::Config;
+use diesel_async::pooled_connection::deadpool::{Pool, PoolError};
+use diesel_async::AsyncPgConnection;
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, error, warn};
+use pgvector::Vector;
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::embedder::{E5Embedder, E5Error};
+use super::queue::{EmbedQueue, DEFAULT_MAX_BATCH_SIZE};
+use crate::db::embeddings;
+use crate::db::models::Item;
+use crate::events::{EventBus, ItemChanged};
+use crate::repository::ItemRepository;
+use crate::storage::StorageError;
+
+/// Stories below these are too quiet to be worth embedding
+const MIN_SCORE: i64 = 20;
+const MIN_DESCENDANTS: i64 = 3;
+
+/// Changes that alter a story's document. Score-only updates don't need a re-embed
+const DOCUMENT_FIELDS: &[&str] = &["title", "text", "descendants"];
+
+/// Tokens a comment too long for one document repeats from the end of its previous window
+const COMMENT_OVERLAP: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum ProcessorError {
+    #[error("Embedding failed: {0:?}")]
+    EmbedError(E5Error),
+
+    #[error(transparent)]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error(transparent)]
+    DBPoolError(#[from] PoolError),
+
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+}
+
+impl From<E5Error> for ProcessorError {
+    fn from(err: E5Error) -> Self {
+        ProcessorError::EmbedError(err)
+    }
+}
 
 pub struct HnProcessor {
-    hn_api_url: String,
-    triton_server_addr: String,
+    embedder: EmbedQueue,
+    items: ItemRepository,
+    db_pool: Pool<AsyncPgConnection>,
+    events: EventBus,
 }
 
 impl HnProcessor {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(
+        embedder: EmbedQueue,
+        items: ItemRepository,
+        db_pool: Pool<AsyncPgConnection>,
+        events: EventBus,
+    ) -> Self {
         Self {
-            hn_api_url: config.hn_api_url.clone(),
-            triton_server_addr: config.triton_server_addr.clone(),
+            embedder,
+            items,
+            db_pool,
+            events,
         }
     }
 
+    /**
+    Embeds stories as the sync workers write them, until the event bus closes.
+
+    Up to a batch worth of stories are embedded at once, so their passages share Triton requests
+    in the queue. Past that, events wait on the bus.
+    */
     pub async fn start(&self) {
-        let embedder = self.embed_and_store();
+        let mut receiver = self.events.subscribe();
+        let mut embeds = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                event = receiver.recv(), if embeds.len() < DEFAULT_MAX_BATCH_SIZE => match event {
+                    Ok(event) if event.kind.as_deref() == Some("story") => {
+                        embeds.push(self.embed_and_store(event))
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => warn!("HnProcessor missed {} item events", n),
+                    Err(RecvError::Closed) => break,
+                },
+                Some(()) = embeds.next() => {}
+            }
+        }
+        while embeds.next().await.is_some() {}
     }
 
-    async fn embed_and_store(&self) {
-        // Boilerplate
+    async fn embed_and_store(&self, event: ItemChanged) {
+        if !event
+            .fields_changed
+            .iter()
+            .any(|field| DOCUMENT_FIELDS.contains(field))
+        {
+            return;
+        }
+        match self.embed_story(event.id).await {
+            Ok(0) => {}
+            Ok(chunks) => debug!("Embedded story {} in {} chunks", event.id, chunks),
+            Err(err) => error!("Could not embed story {}: {}", event.id, err),
+        }
+    }
+
+    /// Re-embeds a story's discussion into the `embeddings` table, returning the number of chunks
+    pub async fn embed_story(&self, story_id: i64) -> Result<usize, ProcessorError> {
+        let thread = self.items.thread(story_id).await?;
+        let Some(story) = thread.first().filter(|story| story.id == story_id) else {
+            return Ok(0);
+        };
+        if story.score.unwrap_or(0) < MIN_SCORE || story.descendants.unwrap_or(0) < MIN_DESCENDANTS
+        {
+            return Ok(0);
+        }
+
+        let parts = document_parts(&thread, self.embedder.embedder())?;
+        let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+        let chunks: Vec<Vector> = self
+            .embedder
+            .embed_passages(&parts)
+            .await?
+            .into_iter()
+            .map(Vector::from)
+            .collect();
+        let n_chunks = chunks.len();
+        let mut conn = self.db_pool.get().await?;
+        embeddings::replace_story(&mut conn, story_id, chunks).await?;
+        Ok(n_chunks)
+    }
+}
+
+/**
+Splits a thread, story first then comments in display order, into documents that fit in one
+passage of `embedder`.
+
+Each document starts with the story's title and text, cut to at most half a passage so comments
+always get the other half, followed by as many comments as fit, indented by their depth. A comment too long to fit on its own
+is split into overlapping windows, one document each.
+*/
+fn document_parts(thread: &[Item], embedder: &E5Embedder) -> Result<Vec<String>, E5Error> {
+    let Some((story, comments)) = thread.split_first() else {
+        return Ok(Vec::new());
+    };
+    let title = story.title.as_deref().map(clean_text).unwrap_or_default();
+    let text = story.text.as_deref().map(clean_text).unwrap_or_default();
+    if title.is_empty() && text.is_empty() {
+        return Ok(Vec::new());
+    }
+    let max_tokens = embedder.max_passage_tokens();
+    let mut header = format!("Topic: {}\n", title);
+    if !text.is_empty() {
+        header.push_str(&text);
+        header.push('\n');
+    }
+    let footer = "Discussion:\n";
+    let header_budget = (max_tokens / 2).saturating_sub(embedder.count_tokens(footer)?);
+    let header = match embedder
+        .chunks(&header, header_budget.max(1), 0)?
+        .as_slice()
+    {
+        [first, _, ..] => format!("{}\n{}", first.text, footer),
+        _ => format!("{}{}", header, footer),
+    };
+    let header_tokens = embedder.count_tokens(&header)?;
+    // Room left for comments in each document
+    let budget = max_tokens
+        .saturating_sub(header_tokens)
+        .max(COMMENT_OVERLAP + 1);
+
+    let mut parts = Vec::new();
+    let mut current = header.clone();
+    let mut current_tokens = 0;
+    for comment in comments {
+        if comment.deleted == Some(true) || comment.dead == Some(true) {
+            continue;
+        }
+        let Some(text) = comment.text.as_deref().map(clean_text) else {
+            continue;
+        };
+        if text.contains("[dead]") || text.contains("[flagged]") {
+            continue;
+        }
+        let indent = "\t".repeat(comment.depth.unwrap_or(1).max(1) as usize - 1);
+        // Whitespace never becomes a token, so lines add up
+        let tokens = embedder.count_tokens(&text)?;
+        if current_tokens + tokens > budget && current_tokens > 0 {
+            parts.push(std::mem::replace(&mut current, header.clone()));
+            current_tokens = 0;
+        }
+        if tokens <= budget {
+            current.push_str(&format!("{}{}\n", indent, text));
+            current_tokens += tokens;
+            continue;
+        }
+        for chunk in embedder.chunks(&text, budget, COMMENT_OVERLAP)? {
+            parts.push(format!("{}{}{}\n", header, indent, chunk.text));
+        }
+    }
+    if current_tokens > 0 || parts.is_empty() {
+        parts.push(current);
+    }
+    Ok(parts)
+}
+
+/// HN HTML as plain text: tags dropped, the entities HN emits decoded
+fn clean_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
     }
+    text.replace("&#x27;", "'")
+        .replace("&#x2F;", "/")
+        .replace("&quot;", "\"")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&")
 }
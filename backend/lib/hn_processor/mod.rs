@@ -1 +1,5 @@
 pub mod embedder;
+mod main;
+pub mod queue;
+
+pub use main::{HnProcessor, ProcessorError};
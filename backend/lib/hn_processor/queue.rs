@@ -0,0 +1,125 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::time::{timeout_at, Instant};
+
+use super::embedder::{E5Embedder, E5Error, PASSAGE_PREFIX, QUERY_PREFIX};
+
+/// Texts per Triton request
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 32;
+/// How long the first text of a batch waits for others to join it
+pub const DEFAULT_MAX_BATCH_DELAY: Duration = Duration::from_millis(5);
+/// Triton requests in flight at once
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+
+/// A text waiting to be embedded, and where its caller waits for the result
+struct Job {
+    text: String,
+    reply: oneshot::Sender<Result<Vec<f32>, E5Error>>,
+}
+
+/**
+Gathers texts from concurrent callers into batched Triton requests.
+
+A batch goes out once it holds `max_batch_size` texts, or `max_delay` after its first text
+arrived, whichever comes first. A lone caller waits at most `max_delay` longer than calling the
+embedder directly, while a backlog is embedded `max_batch_size` texts per request.
+
+At most `max_concurrent_requests` batches are sent at once. Past that, texts wait in the queue,
+and once it's full, callers wait to enqueue.
+*/
+#[derive(Clone)]
+pub struct EmbedQueue {
+    embedder: Arc<E5Embedder>,
+    sender: mpsc::Sender<Job>,
+}
+
+impl EmbedQueue {
+    /// Starts the batching task, which runs until every clone of the queue is dropped
+    pub fn new(
+        embedder: Arc<E5Embedder>,
+        max_batch_size: usize,
+        max_delay: Duration,
+        max_concurrent_requests: usize,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(max_batch_size * 4);
+        tokio::spawn(run(
+            embedder.clone(),
+            receiver,
+            max_batch_size,
+            max_delay,
+            Arc::new(Semaphore::new(max_concurrent_requests)),
+        ));
+        Self { embedder, sender }
+    }
+
+    pub fn embedder(&self) -> &E5Embedder {
+        &self.embedder
+    }
+
+    /// Embedding of a document chunk, for storage
+    pub async fn embed_passage(&self, txt: &str) -> Result<Vec<f32>, E5Error> {
+        self.embed(format!("{}{}", PASSAGE_PREFIX, txt)).await
+    }
+
+    /// Embedding of a search query, to compare against stored passages
+    pub async fn embed_query(&self, txt: &str) -> Result<Vec<f32>, E5Error> {
+        self.embed(format!("{}{}", QUERY_PREFIX, txt)).await
+    }
+
+    /// Embeddings of document chunks in the same order, batched with whatever else is queued
+    pub async fn embed_passages(&self, txts: &[&str]) -> Result<Vec<Vec<f32>>, E5Error> {
+        futures::future::try_join_all(txts.iter().map(|txt| self.embed_passage(txt))).await
+    }
+
+    async fn embed(&self, text: String) -> Result<Vec<f32>, E5Error> {
+        let (reply, result) = oneshot::channel();
+        self.sender
+            .send(Job { text, reply })
+            .await
+            .map_err(|_| E5Error::QueueClosed)?;
+        result.await.map_err(|_| E5Error::QueueClosed)?
+    }
+}
+
+async fn run(
+    embedder: Arc<E5Embedder>,
+    mut receiver: mpsc::Receiver<Job>,
+    max_batch_size: usize,
+    max_delay: Duration,
+    requests: Arc<Semaphore>,
+) {
+    while let Some(first) = receiver.recv().await {
+        let deadline = Instant::now() + max_delay;
+        let mut batch = vec![first];
+        while batch.len() < max_batch_size {
+            match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(job)) => batch.push(job),
+                // Out of time, or the queue is shutting down: send what we have
+                Ok(None) | Err(_) => break,
+            }
+        }
+        // Batches overlap, so Triton can work on one while the next fills up
+        let Ok(permit) = requests.clone().acquire_owned().await else {
+            break;
+        };
+        let embedder = embedder.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            let texts: Vec<&str> = batch.iter().map(|job| job.text.as_str()).collect();
+            match embedder.embed(&texts).await {
+                Ok(embeddings) => {
+                    for (job, embedding) in batch.into_iter().zip(embeddings) {
+                        // The caller may have given up waiting
+                        let _ = job.reply.send(Ok(embedding));
+                    }
+                }
+                Err(err) => {
+                    for job in batch {
+                        let _ = job.reply.send(Err(err.clone()));
+                    }
+                }
+            }
+        });
+    }
+}
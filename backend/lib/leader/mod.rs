@@ -0,0 +1,127 @@
+use diesel::sql_types::{BigInt, Bool};
+use diesel::{sql_query, ConnectionError, QueryableByName};
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl, SimpleAsyncConnection};
+use log::{debug, info, warn};
+use std::time::Duration;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+/// Advisory lock key guarding HN ingestion. Arbitrary, but must match across replicas
+pub const SYNC_LOCK_KEY: i64 = 0x0069_6e73_7468_726e;
+
+/// How often a standby retries the lock
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// How often the leader checks that its lock session is still alive
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long that check may take before the leader assumes its session is gone
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+Server settings for the lock session, so Postgres drops it, and with it the lock, soon after the
+leader stops answering:
+- a hung leader stops sending its checks, and is cut off by `idle_session_timeout` (Postgres 14+)
+- an unreachable one fails the keepalive probes after 10 + 3 * 5 seconds, or `tcp_user_timeout`
+  (Postgres 12+) if the server was sending it data
+
+Either way the lock is free about 30 seconds after the leader went silent. By then the leader's
+own check has failed through `CHECK_TIMEOUT`, so it has stopped ingesting.
+*/
+const SESSION_SETTINGS: &[&str] = &[
+    "SET idle_session_timeout = '30s'",
+    "SET tcp_keepalives_idle = 10",
+    "SET tcp_keepalives_interval = 5",
+    "SET tcp_keepalives_count = 3",
+    "SET tcp_user_timeout = '25s'",
+];
+
+#[derive(Error, Debug)]
+pub enum LeaderError {
+    #[error("Could not connect to Postgres: {0}")]
+    ConnectError(#[from] ConnectionError),
+
+    #[error(transparent)]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error("Lock session did not answer within {0:?}")]
+    CheckTimeout(Duration),
+}
+
+#[derive(QueryableByName)]
+struct LockResult {
+    #[diesel(sql_type = Bool)]
+    locked: bool,
+}
+
+/**
+Leader election over a Postgres session-level advisory lock.
+
+The lock lives on a dedicated connection rather than a pooled one, so it's held exactly as
+long as that session: if the leader dies or loses its connection, Postgres releases the lock
+and the next standby to retry takes over.
+*/
+pub struct LeaderElection {
+    db_url: String,
+    lock_key: i64,
+}
+
+impl LeaderElection {
+    pub fn new(db_url: String, lock_key: i64) -> Self {
+        Self { db_url, lock_key }
+    }
+
+    /// Waits until this replica holds the lock. Returns `None` if cancelled first
+    pub async fn acquire(
+        &self,
+        cancel_token: &CancellationToken,
+    ) -> Result<Option<Leadership>, LeaderError> {
+        let mut conn = AsyncPgConnection::establish(&self.db_url).await?;
+        for setting in SESSION_SETTINGS {
+            // Older servers lack some of these; the others still bound the takeover
+            if let Err(err) = conn.batch_execute(setting).await {
+                warn!("Leader lock session could not `{}`: {}", setting, err);
+            }
+        }
+        loop {
+            let result: LockResult = sql_query("SELECT pg_try_advisory_lock($1) AS locked")
+                .bind::<BigInt, _>(self.lock_key)
+                .get_result(&mut conn)
+                .await?;
+            if result.locked {
+                info!("Acquired leader lock {}", self.lock_key);
+                return Ok(Some(Leadership { conn }));
+            }
+            debug!(
+                "Leader lock held elsewhere, retrying in {:?}",
+                RETRY_INTERVAL
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                _ = cancel_token.cancelled() => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Held by the current leader. Dropping it closes the lock session, releasing leadership
+pub struct Leadership {
+    conn: AsyncPgConnection,
+}
+
+impl Leadership {
+    /**
+    Resolves once the lock session fails, or stops answering for `CHECK_TIMEOUT`. Either way
+    another replica may soon lead, so ingestion has to stop.
+    */
+    pub async fn lost(&mut self) -> LeaderError {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            match tokio::time::timeout(CHECK_TIMEOUT, sql_query("SELECT 1").execute(&mut self.conn))
+                .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => return err.into(),
+                Err(_) => return LeaderError::CheckTimeout(CHECK_TIMEOUT),
+            }
+        }
+    }
+}
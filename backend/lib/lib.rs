@@ -1,6 +1,12 @@
+pub mod change_feed;
 pub mod config;
 pub mod db;
+pub mod events;
+pub mod export;
 pub mod firebase_listener;
 pub mod hn_processor;
+pub mod leader;
+pub mod repository;
+pub mod storage;
 pub mod sync_service;
 pub mod triton;
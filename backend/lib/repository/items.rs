@@ -0,0 +1,84 @@
+use chrono::{DateTime, Utc};
+use std::ops::{Range, RangeInclusive};
+use std::sync::Arc;
+
+use crate::db::models::Item;
+use crate::db::search::{SearchHit, SearchQuery};
+use crate::storage::{Storage, StorageError};
+
+/// Typed queries over `items`
+#[derive(Clone)]
+pub struct ItemRepository {
+    storage: Arc<dyn Storage>,
+}
+
+impl ItemRepository {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn get(&self, id: i64) -> Result<Option<Item>, StorageError> {
+        Ok(self.storage.get_items(&[id]).await?.pop())
+    }
+
+    /// The items among `ids` that exist, in id order
+    pub async fn get_many(&self, ids: &[i64]) -> Result<Vec<Item>, StorageError> {
+        self.storage.get_items(ids).await
+    }
+
+    /// Direct replies to `id`, in the order HN displays them
+    pub async fn children(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        self.storage.children(id).await
+    }
+
+    /// `id` and every reply below it, depth-first in display order
+    pub async fn thread(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        self.storage.thread(id).await
+    }
+
+    /// Stories submitted by `by`, newest first, optionally only those created before `before`
+    pub async fn stories_by_user(
+        &self,
+        by: &str,
+        before: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError> {
+        self.storage.stories_by_user(by, before, limit).await
+    }
+
+    /// Items created in `range`, oldest first, optionally only of type `kind`
+    pub async fn in_time_range(
+        &self,
+        range: Range<DateTime<Utc>>,
+        kind: Option<&str>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Item>, StorageError> {
+        self.storage.items_in_time_range(range, kind, limit).await
+    }
+
+    /// Full-text search, see `SearchQuery`. Postgres only
+    pub async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, StorageError> {
+        self.storage.search(query).await
+    }
+
+    /// Highest id stored, optionally only among `ids`
+    pub async fn max_id(
+        &self,
+        ids: Option<RangeInclusive<i64>>,
+    ) -> Result<Option<i64>, StorageError> {
+        self.storage.max_id(ids).await
+    }
+
+    /// Ids in `ids` that are stored
+    pub async fn existing_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError> {
+        self.storage.existing_ids(ids).await
+    }
+
+    pub async fn create(&self, item: &Item) -> Result<usize, StorageError> {
+        self.storage.create_item(item).await
+    }
+
+    pub async fn delete(&self, id: i64) -> Result<usize, StorageError> {
+        self.storage.delete_item(id).await
+    }
+}
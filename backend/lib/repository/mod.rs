@@ -0,0 +1,4 @@
+mod items;
+mod users;
+pub use items::ItemRepository;
+pub use users::UserRepository;
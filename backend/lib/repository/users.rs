@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use crate::db::models::User;
+use crate::storage::{Storage, StorageError};
+
+/// Typed queries over `users`
+#[derive(Clone)]
+pub struct UserRepository {
+    storage: Arc<dyn Storage>,
+}
+
+impl UserRepository {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<User>, StorageError> {
+        Ok(self.storage.get_users(&[id]).await?.pop())
+    }
+
+    /// The users among `ids` that exist, in id order
+    pub async fn get_many(&self, ids: &[&str]) -> Result<Vec<User>, StorageError> {
+        self.storage.get_users(ids).await
+    }
+
+    /// Inserts `user`, or overwrites every column of the stored profile if it exists
+    pub async fn upsert(&self, user: &User) -> Result<usize, StorageError> {
+        self.storage.upsert_user(user).await
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<usize, StorageError> {
+        self.storage.delete_user(id).await
+    }
+}
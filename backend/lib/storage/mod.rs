@@ -0,0 +1,307 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::ConnectionError;
+use diesel_async::pooled_connection::deadpool::PoolError;
+use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Range, RangeInclusive};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinError;
+
+mod postgres;
+mod sqlite;
+pub use postgres::PgStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::change_feed::{ChangeOp, ItemChange};
+use crate::db::models::{Item, Kid, User};
+use crate::db::search::{SearchHit, SearchQuery};
+use crate::events::ItemChanged;
+
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Could not connect: {0}")]
+    ConnectError(#[from] ConnectionError),
+
+    #[error(transparent)]
+    DieselError(#[from] diesel::result::Error),
+
+    #[error(transparent)]
+    DBPoolError(#[from] PoolError),
+
+    #[error("No DB connection freed up within {waited:?}: all {max_size} are in use. Raise DB_POOL_SIZE or lower the number of workers")]
+    PoolExhausted { waited: Duration, max_size: usize },
+
+    #[error("{0} needs Postgres")]
+    Unsupported(&'static str),
+
+    #[error("Task join error: {0}")]
+    TaskJoinError(#[from] JoinError),
+}
+
+/**
+Everything the sync service and the repositories read from or write to the database.
+
+`PgStorage` is the production backend. `SqliteStorage` keeps a personal mirror in a single file,
+without full-text search, partitions or the change feed.
+*/
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// The items among `ids` that exist, in id order
+    async fn get_items(&self, ids: &[i64]) -> Result<Vec<Item>, StorageError>;
+
+    /// Stored `kids` rows of the items among `ids`
+    async fn get_kids(&self, ids: &[i64]) -> Result<Vec<Kid>, StorageError>;
+
+    /// Direct replies to `id`, in the order HN displays them
+    async fn children(&self, id: i64) -> Result<Vec<Item>, StorageError>;
+
+    /// `id` and every reply below it, depth-first in display order
+    async fn thread(&self, id: i64) -> Result<Vec<Item>, StorageError>;
+
+    /// Stories submitted by `by`, newest first, optionally only those created before `before`
+    async fn stories_by_user(
+        &self,
+        by: &str,
+        before: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError>;
+
+    /// Items created in `range`, oldest first, optionally only of type `kind`
+    async fn items_in_time_range(
+        &self,
+        range: Range<DateTime<Utc>>,
+        kind: Option<&str>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Item>, StorageError>;
+
+    /// Full-text search, see `SearchQuery`
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, StorageError>;
+
+    /// Highest id stored, optionally only among `ids`
+    async fn max_id(&self, ids: Option<RangeInclusive<i64>>) -> Result<Option<i64>, StorageError>;
+
+    /// Ids in `ids` that are stored
+    async fn existing_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError>;
+
+    /// The first `limit` stored items in `ids`, in id order. For paging through the whole table
+    async fn scan_items(
+        &self,
+        ids: RangeInclusive<i64>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError>;
+
+    async fn create_item(&self, item: &Item) -> Result<usize, StorageError>;
+
+    async fn delete_item(&self, id: i64) -> Result<usize, StorageError>;
+
+    /// The users among `ids` that exist, in id order
+    async fn get_users(&self, ids: &[&str]) -> Result<Vec<User>, StorageError>;
+
+    /// The first `limit` users with ids after `after`, in id order
+    async fn scan_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, StorageError>;
+
+    /// Inserts `user`, or overwrites every column of the stored profile if it exists.
+    /// Either way, it's recorded in `changed_users` for the current day
+    async fn upsert_user(&self, user: &User) -> Result<usize, StorageError>;
+
+    async fn delete_user(&self, id: &str) -> Result<usize, StorageError>;
+
+    /**
+    Upserts a batch of items, and replaces their `kids` rows so they match each item's current `kids` list.
+    Once committed, resolves `story_id`, `depth` and `path` for the batch and any descendants waiting on it.
+
+    Returns the inserted items and the updated ones whose columns changed, which are also recorded
+    in `changed_items` for the current day.
+    */
+    async fn upsert_items(
+        &self,
+        items: &[Item],
+        kids: &[Kid],
+    ) -> Result<Vec<ItemChanged>, StorageError>;
+
+    /// Ids in `ids` still missing `story_id` or `path`
+    async fn unresolved_thread_ids(
+        &self,
+        ids: RangeInclusive<i64>,
+    ) -> Result<Vec<i64>, StorageError>;
+
+    /// Resolves `story_id`, `depth` and `path` for `ids` and their descendants, returning how many of each were updated
+    async fn resolve_threads(&self, ids: &[i64]) -> Result<(usize, usize), StorageError>;
+
+    /// Ids in `ids` recorded as `null` on HN
+    async fn empty_item_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError>;
+
+    /// Records ids HN answered with `null`, so backfills skip them
+    async fn save_empty_items(&self, ids: &[i64]) -> Result<(), StorageError>;
+
+    /// Saves ids to `pending_updates` to be re-queued on the next start
+    async fn save_pending_updates(&self, ids: &[i64]) -> Result<(), StorageError>;
+
+    /// Removes and returns all ids left in `pending_updates`
+    async fn take_pending_updates(&self) -> Result<Vec<i64>, StorageError>;
+
+    /// Ids of the items recorded as inserted or changed on `day` (UTC), in id order
+    async fn changed_item_ids(&self, day: NaiveDate) -> Result<Vec<i64>, StorageError>;
+
+    /// Ids of the users recorded as upserted on `day` (UTC), in id order
+    async fn changed_user_ids(&self, day: NaiveDate) -> Result<Vec<String>, StorageError>;
+
+    /// Forgets the changes recorded before `day`, returning how many rows were removed
+    async fn prune_changes(&self, before: NaiveDate) -> Result<usize, StorageError>;
+
+    /// Creates whatever is needed to hold ids up to `max_id`, returning the names of new partitions
+    async fn ensure_partitions(&self, max_id: i64) -> Result<Vec<String>, StorageError>;
+}
+
+/// Whether `db_url` names a SQLite file rather than a Postgres server
+pub fn is_sqlite_url(db_url: &str) -> bool {
+    db_url.starts_with("sqlite:") || db_url.ends_with(".db") || db_url.ends_with(".sqlite")
+}
+
+/// The file path in a SQLite `db_url`
+pub(crate) fn sqlite_path(db_url: &str) -> &str {
+    db_url
+        .strip_prefix("sqlite://")
+        .or_else(|| db_url.strip_prefix("sqlite:"))
+        .unwrap_or(db_url)
+}
+
+/**
+Feed entries and events for a batch about to be upserted over `existing`, the stored versions of its items.
+
+Updates that leave every column as stored are dropped from both, so the NOTIFY feed and the
+event bus announce the same set of items.
+*/
+fn item_changes(
+    existing: &HashMap<i64, Item>,
+    items: &[Item],
+) -> (Vec<ItemChange>, Vec<ItemChanged>) {
+    let changed: Vec<ItemChanged> = items
+        .iter()
+        .filter_map(|item| {
+            let (op, fields_changed) = match existing.get(&item.id) {
+                Some(old) => (ChangeOp::Update, item.changed_fields(old)),
+                None => (
+                    ChangeOp::Insert,
+                    item.changed_fields(&Item {
+                        id: item.id,
+                        ..Default::default()
+                    }),
+                ),
+            };
+            if op == ChangeOp::Update && fields_changed.is_empty() {
+                return None;
+            }
+            Some(ItemChanged {
+                id: item.id,
+                kind: item.type_.clone(),
+                op,
+                fields_changed,
+            })
+        })
+        .collect();
+    let changes = changed
+        .iter()
+        .map(|c| ItemChange {
+            id: c.id,
+            kind: c.kind.clone(),
+            op: c.op,
+        })
+        .collect();
+    (changes, changed)
+}
+
+/**
+Stored `kids` rows that are no longer in their parent's `kids` list, grouped by parent.
+
+Every removal is logged under the `kids_removed` target, so detached comments can be studied
+by filtering on it, e.g. `RUST_LOG=kids_removed=info`.
+*/
+fn detached_kids(stored: Vec<Kid>, kids: &[Kid]) -> BTreeMap<i64, Vec<i64>> {
+    let current: HashSet<(i64, i64)> = kids.iter().map(|k| (k.item, k.kid)).collect();
+    let mut removed: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    for kid in stored {
+        if !current.contains(&(kid.item, kid.kid)) {
+            info!(
+                target: "kids_removed",
+                "item={} kid={} display_order={:?}",
+                kid.item,
+                kid.kid,
+                kid.display_order
+            );
+            removed.entry(kid.item).or_default().push(kid.kid);
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detached_kids, item_changes};
+    use crate::change_feed::ChangeOp;
+    use crate::db::models::{Item, Kid};
+    use std::collections::{BTreeMap, HashMap};
+
+    fn kid(item: i64, kid: i64, display_order: i64) -> Kid {
+        Kid {
+            item,
+            kid,
+            display_order: Some(display_order),
+        }
+    }
+
+    fn story(id: i64, score: i64) -> Item {
+        Item {
+            id,
+            type_: Some("story".into()),
+            title: Some("Hello".into()),
+            score: Some(score),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detached_kids_groups_removed_kids_by_parent() {
+        let stored = vec![kid(1, 10, 0), kid(1, 11, 1), kid(1, 12, 2), kid(2, 20, 0)];
+        let current = [kid(1, 11, 0), kid(2, 20, 0)];
+        assert_eq!(
+            detached_kids(stored, &current),
+            BTreeMap::from([(1, vec![10, 12])])
+        );
+    }
+
+    #[test]
+    fn detached_kids_ignores_reordering() {
+        let stored = vec![kid(1, 10, 0), kid(1, 11, 1)];
+        let current = [kid(1, 11, 0), kid(1, 10, 1)];
+        assert!(detached_kids(stored, &current).is_empty());
+    }
+
+    #[test]
+    fn item_changes_reports_inserts_with_every_set_column() {
+        let (changes, changed) = item_changes(&HashMap::new(), &[story(1, 10)]);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].op, ChangeOp::Insert);
+        assert_eq!(changes[0].kind.as_deref(), Some("story"));
+        assert_eq!(changed[0].fields_changed, vec!["type", "score", "title"]);
+    }
+
+    #[test]
+    fn item_changes_reports_only_changed_columns_of_updates() {
+        let existing = HashMap::from([(1, story(1, 10))]);
+        let (changes, changed) = item_changes(&existing, &[story(1, 11)]);
+        assert_eq!(changes[0].op, ChangeOp::Update);
+        assert_eq!(changed[0].op, ChangeOp::Update);
+        assert_eq!(changed[0].fields_changed, vec!["score"]);
+    }
+
+    #[test]
+    fn item_changes_drops_no_op_updates_from_both_feeds() {
+        let existing = HashMap::from([(1, story(1, 10)), (2, story(2, 5))]);
+        let (changes, changed) = item_changes(&existing, &[story(1, 10), story(2, 6)]);
+        assert_eq!(changes.iter().map(|c| c.id).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(changed.iter().map(|c| c.id).collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,439 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use deadpool::managed::TimeoutType;
+use diesel::pg::upsert::excluded;
+use diesel::prelude::*;
+use diesel::{delete, insert_into};
+use diesel_async::pooled_connection::deadpool::{Object, Pool, PoolError};
+use diesel_async::scoped_futures::ScopedFutureExt;
+use diesel_async::{AsyncConnection, AsyncPgConnection, RunQueryDsl};
+use std::collections::HashMap;
+use std::ops::{Range, RangeInclusive};
+
+use super::{detached_kids, item_changes, Storage, StorageError};
+use crate::change_feed;
+use crate::db::models::{Item, Kid, User};
+use crate::db::schema::{
+    changed_items, changed_users, empty_items, items, kids, pending_updates, users,
+};
+use crate::db::search::{self, SearchHit, SearchQuery};
+use crate::db::{partitions, threads};
+use crate::events::ItemChanged;
+
+/// Postgres storage, each call on its own pooled connection
+#[derive(Clone)]
+pub struct PgStorage {
+    db_pool: Pool<AsyncPgConnection>,
+}
+
+impl PgStorage {
+    pub fn new(db_pool: Pool<AsyncPgConnection>) -> Self {
+        Self { db_pool }
+    }
+
+    /// A pooled connection, or `PoolExhausted` if none frees up within the pool's wait timeout
+    async fn conn(&self) -> Result<Object<AsyncPgConnection>, StorageError> {
+        self.db_pool.get().await.map_err(|err| match err {
+            PoolError::Timeout(TimeoutType::Wait) => StorageError::PoolExhausted {
+                waited: self.db_pool.timeouts().wait.unwrap_or_default(),
+                max_size: self.db_pool.status().max_size,
+            },
+            err => err.into(),
+        })
+    }
+}
+
+/**
+Deletes `kids` rows of the batch's items that are no longer in their parent's `kids` list,
+and clears the paths of the subtrees they detach.
+*/
+async fn remove_detached_kids(
+    conn: &mut AsyncPgConnection,
+    items_batch: &[Item],
+    kids_batch: &[Kid],
+) -> QueryResult<()> {
+    let item_ids: Vec<i64> = items_batch.iter().map(|item| item.id).collect();
+    let stored: Vec<Kid> = kids::table
+        .select((kids::item, kids::kid, kids::display_order))
+        .filter(kids::item.eq_any(&item_ids))
+        .load(conn)
+        .await?;
+
+    let removed = detached_kids(stored, kids_batch);
+    let removed_ids: Vec<i64> = removed.values().flatten().copied().collect();
+    for (item, removed_kids) in removed {
+        delete(
+            kids::table
+                .filter(kids::item.eq(item))
+                .filter(kids::kid.eq_any(removed_kids)),
+        )
+        .execute(conn)
+        .await?;
+    }
+    // Detached subtrees no longer have a place in the thread
+    if !removed_ids.is_empty() {
+        threads::clear_paths(conn, &removed_ids).await?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for PgStorage {
+    async fn get_items(&self, ids: &[i64]) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(items::table
+            .filter(items::id.eq_any(ids))
+            .select(Item::as_select())
+            .order(items::id)
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn get_kids(&self, ids: &[i64]) -> Result<Vec<Kid>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(kids::table
+            .select((kids::item, kids::kid, kids::display_order))
+            .filter(kids::item.eq_any(ids))
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn children(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(kids::table
+            .inner_join(items::table.on(items::id.eq(kids::kid)))
+            .filter(kids::item.eq(id))
+            .order(kids::display_order)
+            .select(Item::as_select())
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn thread(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(threads::subtree(&mut conn, id).await?)
+    }
+
+    async fn stories_by_user(
+        &self,
+        by: &str,
+        before: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        let mut query = items::table
+            .filter(items::by.eq(by))
+            .filter(items::type_.eq("story"))
+            .select(Item::as_select())
+            .order(items::created_at.desc())
+            .limit(limit)
+            .into_boxed();
+        if let Some(before) = before {
+            query = query.filter(items::created_at.lt(before));
+        }
+        Ok(query.load(&mut conn).await?)
+    }
+
+    async fn items_in_time_range(
+        &self,
+        range: Range<DateTime<Utc>>,
+        kind: Option<&str>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        let mut query = items::table
+            .filter(items::created_at.ge(range.start))
+            .filter(items::created_at.lt(range.end))
+            .select(Item::as_select())
+            .order(items::created_at)
+            .into_boxed();
+        if let Some(kind) = kind {
+            query = query.filter(items::type_.eq(kind));
+        }
+        if let Some(limit) = limit {
+            query = query.limit(limit);
+        }
+        Ok(query.load(&mut conn).await?)
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Vec<SearchHit>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(search::search(&mut conn, query).await?)
+    }
+
+    async fn max_id(&self, ids: Option<RangeInclusive<i64>>) -> Result<Option<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        let ids = ids.unwrap_or(i64::MIN..=i64::MAX);
+        Ok(items::table
+            .select(diesel::dsl::max(items::id))
+            .filter(items::id.between(*ids.start(), *ids.end()))
+            .first(&mut conn)
+            .await?)
+    }
+
+    async fn existing_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(items::table
+            .select(items::id)
+            .filter(items::id.between(*ids.start(), *ids.end()))
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn scan_items(
+        &self,
+        ids: RangeInclusive<i64>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(items::table
+            .filter(items::id.between(*ids.start(), *ids.end()))
+            .select(Item::as_select())
+            .order(items::id)
+            .limit(limit)
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn create_item(&self, item: &Item) -> Result<usize, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(insert_into(items::table)
+            .values(item)
+            .execute(&mut conn)
+            .await?)
+    }
+
+    async fn delete_item(&self, id: i64) -> Result<usize, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(delete(items::table.find(id)).execute(&mut conn).await?)
+    }
+
+    async fn get_users(&self, ids: &[&str]) -> Result<Vec<User>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(users::table
+            .filter(users::id.eq_any(ids))
+            .select(User::as_select())
+            .order(users::id)
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn scan_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, StorageError> {
+        let mut conn = self.conn().await?;
+        let mut query = users::table
+            .select(User::as_select())
+            .order(users::id)
+            .limit(limit)
+            .into_boxed();
+        if let Some(after) = after {
+            query = query.filter(users::id.gt(after));
+        }
+        Ok(query.load(&mut conn).await?)
+    }
+
+    async fn upsert_user(&self, user: &User) -> Result<usize, StorageError> {
+        let mut conn = self.conn().await?;
+        conn.transaction::<_, StorageError, _>(|conn| {
+            async move {
+                let upserted = insert_into(users::table)
+                    .values(user)
+                    .on_conflict(users::id)
+                    .do_update()
+                    .set(user)
+                    .execute(conn)
+                    .await?;
+                insert_into(changed_users::table)
+                    .values((
+                        changed_users::day.eq(Utc::now().date_naive()),
+                        changed_users::id.eq(&user.id),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)
+                    .await?;
+                Ok(upserted)
+            }
+            .scope_boxed()
+        })
+        .await
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<usize, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(delete(users::table.find(id)).execute(&mut conn).await?)
+    }
+
+    /// Each upserted item is also announced on the `item_changes` channel when the transaction commits
+    async fn upsert_items(
+        &self,
+        items_batch: &[Item],
+        kids_batch: &[Kid],
+    ) -> Result<Vec<ItemChanged>, StorageError> {
+        let mut conn = self.conn().await?;
+        let changed = conn
+            .transaction::<_, StorageError, _>(|conn| {
+                async move {
+                    let item_ids: Vec<i64> = items_batch.iter().map(|item| item.id).collect();
+                    let existing: HashMap<i64, Item> = items::table
+                        .filter(items::id.eq_any(&item_ids))
+                        .select(Item::as_select())
+                        .load::<Item>(conn)
+                        .await?
+                        .into_iter()
+                        .map(|item| (item.id, item))
+                        .collect();
+
+                    insert_into(items::table)
+                        .values(items_batch)
+                        .on_conflict(items::id)
+                        .do_update()
+                        .set((
+                            items::deleted.eq(excluded(items::deleted)),
+                            items::type_.eq(excluded(items::type_)),
+                            items::by.eq(excluded(items::by)),
+                            items::time.eq(excluded(items::time)),
+                            items::text.eq(excluded(items::text)),
+                            items::dead.eq(excluded(items::dead)),
+                            items::parent.eq(excluded(items::parent)),
+                            items::poll.eq(excluded(items::poll)),
+                            items::url.eq(excluded(items::url)),
+                            items::score.eq(excluded(items::score)),
+                            items::title.eq(excluded(items::title)),
+                            items::parts.eq(excluded(items::parts)),
+                            items::descendants.eq(excluded(items::descendants)),
+                        ))
+                        .execute(conn)
+                        .await?;
+
+                    remove_detached_kids(conn, items_batch, kids_batch).await?;
+
+                    insert_into(kids::table)
+                        .values(kids_batch)
+                        .on_conflict((kids::item, kids::kid))
+                        .do_update()
+                        .set(kids::display_order.eq(excluded(kids::display_order)))
+                        .execute(conn)
+                        .await?;
+
+                    let (changes, changed) = item_changes(&existing, items_batch);
+                    change_feed::notify(conn, &changes).await?;
+                    if !changed.is_empty() {
+                        let day = Utc::now().date_naive();
+                        let rows: Vec<_> = changed
+                            .iter()
+                            .map(|c| (changed_items::day.eq(day), changed_items::id.eq(c.id)))
+                            .collect();
+                        insert_into(changed_items::table)
+                            .values(&rows)
+                            .on_conflict_do_nothing()
+                            .execute(conn)
+                            .await?;
+                    }
+                    Ok(changed)
+                }
+                .scope_boxed()
+            })
+            .await?;
+
+        // Resolved after commit, so a concurrent batch holding this one's parents or children
+        // is either visible here, or will see this batch when it resolves after its own commit
+        let item_ids: Vec<i64> = items_batch.iter().map(|item| item.id).collect();
+        threads::resolve_story_ids(&mut conn, &item_ids).await?;
+        threads::resolve_paths(&mut conn, &item_ids).await?;
+        Ok(changed)
+    }
+
+    async fn unresolved_thread_ids(
+        &self,
+        ids: RangeInclusive<i64>,
+    ) -> Result<Vec<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(items::table
+            .select(items::id)
+            .filter(items::id.between(*ids.start(), *ids.end()))
+            .filter(items::story_id.is_null().or(items::path.is_null()))
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn resolve_threads(&self, ids: &[i64]) -> Result<(usize, usize), StorageError> {
+        let mut conn = self.conn().await?;
+        let story_ids = threads::resolve_story_ids(&mut conn, ids).await?;
+        let paths = threads::resolve_paths(&mut conn, ids).await?;
+        Ok((story_ids, paths))
+    }
+
+    async fn empty_item_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(empty_items::table
+            .select(empty_items::id)
+            .filter(empty_items::id.between(*ids.start(), *ids.end()))
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn save_empty_items(&self, ids: &[i64]) -> Result<(), StorageError> {
+        let rows: Vec<_> = ids.iter().map(|id| empty_items::id.eq(*id)).collect();
+        let mut conn = self.conn().await?;
+        insert_into(empty_items::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn save_pending_updates(&self, ids: &[i64]) -> Result<(), StorageError> {
+        let rows: Vec<_> = ids.iter().map(|id| pending_updates::id.eq(*id)).collect();
+        let mut conn = self.conn().await?;
+        insert_into(pending_updates::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn take_pending_updates(&self) -> Result<Vec<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(delete(pending_updates::table)
+            .returning(pending_updates::id)
+            .get_results(&mut conn)
+            .await?)
+    }
+
+    async fn changed_item_ids(&self, day: NaiveDate) -> Result<Vec<i64>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(changed_items::table
+            .select(changed_items::id)
+            .filter(changed_items::day.eq(day))
+            .order(changed_items::id)
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn changed_user_ids(&self, day: NaiveDate) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(changed_users::table
+            .select(changed_users::id)
+            .filter(changed_users::day.eq(day))
+            .order(changed_users::id)
+            .load(&mut conn)
+            .await?)
+    }
+
+    async fn prune_changes(&self, before: NaiveDate) -> Result<usize, StorageError> {
+        let mut conn = self.conn().await?;
+        let items = delete(changed_items::table.filter(changed_items::day.lt(before)))
+            .execute(&mut conn)
+            .await?;
+        let users = delete(changed_users::table.filter(changed_users::day.lt(before)))
+            .execute(&mut conn)
+            .await?;
+        Ok(items + users)
+    }
+
+    async fn ensure_partitions(&self, max_id: i64) -> Result<Vec<String>, StorageError> {
+        let mut conn = self.conn().await?;
+        Ok(partitions::ensure_partitions(&mut conn, max_id).await?)
+    }
+}
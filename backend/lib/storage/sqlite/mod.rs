@@ -0,0 +1,706 @@
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::upsert::excluded;
+use diesel::{delete, insert_into};
+use std::collections::HashMap;
+use std::ops::{Range, RangeInclusive};
+use std::sync::{Arc, Mutex, PoisonError};
+
+mod schema;
+
+use self::schema::items;
+use super::{detached_kids, item_changes, sqlite_path, Storage, StorageError};
+use crate::db::models::{Item, Kid, User};
+use crate::db::schema::{changed_items, changed_users, empty_items, kids, pending_updates, users};
+use crate::db::search::{SearchHit, SearchQuery};
+use crate::events::ItemChanged;
+
+/// Same walk as the Postgres `RESOLVE_STORY_IDS`, with the ids bound as a JSON array
+const RESOLVE_STORY_IDS: &str = "
+WITH RECURSIVE tree (id, story_id, depth) AS (
+    SELECT i.id,
+           CASE WHEN p.id IS NULL THEN i.id ELSE p.story_id END,
+           CASE WHEN p.id IS NULL THEN 0 ELSE p.depth + 1 END
+    FROM items i
+    LEFT JOIN items p ON p.id = COALESCE(i.parent, i.poll)
+    WHERE i.id IN (SELECT value FROM json_each(?))
+      AND (COALESCE(i.parent, i.poll) IS NULL OR p.story_id IS NOT NULL)
+  UNION ALL
+    SELECT c.id, t.story_id, t.depth + 1
+    FROM items c
+    JOIN tree t ON COALESCE(c.parent, c.poll) = t.id
+    WHERE c.story_id IS NOT t.story_id
+       OR c.depth IS NOT t.depth + 1
+)
+UPDATE items
+SET story_id = tree.story_id, depth = tree.depth
+FROM tree
+WHERE items.id = tree.id
+  AND (items.story_id IS NOT tree.story_id OR items.depth IS NOT tree.depth)
+";
+
+/// Same walk as the Postgres `RESOLVE_PATHS`. Labels are fixed-width, so paths sort as text
+const RESOLVE_PATHS: &str = "
+WITH RECURSIVE tree (id, path) AS (
+    SELECT i.id,
+           CASE WHEN i.parent IS NULL THEN CAST(i.id AS TEXT)
+                ELSE p.path || '.' || printf('%06d', k.display_order) END
+    FROM items i
+    LEFT JOIN items p ON p.id = i.parent
+    LEFT JOIN kids k ON k.item = i.parent AND k.kid = i.id
+    WHERE i.id IN (SELECT value FROM json_each(?))
+      AND (i.parent IS NULL OR (p.path IS NOT NULL AND k.display_order IS NOT NULL))
+  UNION ALL
+    SELECT c.id, t.path || '.' || printf('%06d', k.display_order)
+    FROM tree t
+    JOIN kids k ON k.item = t.id
+    JOIN items c ON c.id = k.kid
+    WHERE k.display_order IS NOT NULL
+      AND c.path IS NOT t.path || '.' || printf('%06d', k.display_order)
+)
+UPDATE items
+SET path = tree.path
+FROM tree
+WHERE items.id = tree.id
+  AND items.path IS NOT tree.path
+";
+
+/// Clears `path` for the given items and everything below them. `'/'` sorts right after `'.'`
+const CLEAR_PATHS: &str = "
+UPDATE items
+SET path = NULL
+WHERE id IN (
+    SELECT d.id
+    FROM items r
+    JOIN items d ON d.path = r.path OR (d.path > r.path || '.' AND d.path < r.path || '/')
+    WHERE r.id IN (SELECT value FROM json_each(?)) AND r.path IS NOT NULL
+)
+";
+
+/// Columns mapped by `Item`. Together with `time`, they're everything but `path`
+type ItemColumns = (
+    items::id,
+    items::deleted,
+    items::type_,
+    items::by,
+    items::time,
+    items::text,
+    items::dead,
+    items::parent,
+    items::poll,
+    items::url,
+    items::score,
+    items::title,
+    items::parts,
+    items::descendants,
+    items::story_id,
+    items::depth,
+);
+
+const ITEM_COLUMNS: ItemColumns = (
+    items::id,
+    items::deleted,
+    items::type_,
+    items::by,
+    items::time,
+    items::text,
+    items::dead,
+    items::parent,
+    items::poll,
+    items::url,
+    items::score,
+    items::title,
+    items::parts,
+    items::descendants,
+    items::story_id,
+    items::depth,
+);
+
+type ItemRow = (
+    i64,
+    Option<bool>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<bool>,
+    Option<i64>,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    Option<i32>,
+);
+
+/// An `ItemRow` as an `Item`, with `created_at` derived from `time` as Postgres does
+fn item_from_row(row: ItemRow) -> Item {
+    Item {
+        id: row.0,
+        deleted: row.1,
+        type_: row.2,
+        by: row.3,
+        time: row.4,
+        text: row.5,
+        dead: row.6,
+        parent: row.7,
+        poll: row.8,
+        url: row.9,
+        score: row.10,
+        title: row.11,
+        parts: row.12,
+        descendants: row.13,
+        story_id: row.14,
+        depth: row.15,
+        created_at: row.4.and_then(|time| DateTime::from_timestamp(time, 0)),
+    }
+}
+
+/// An `Item` as an `items` row. `path` is left for `RESOLVE_PATHS` to fill in
+#[derive(Insertable)]
+#[diesel(table_name = items)]
+struct NewItem<'a> {
+    id: i64,
+    deleted: Option<bool>,
+    type_: Option<&'a str>,
+    by: Option<&'a str>,
+    time: Option<i64>,
+    text: Option<&'a str>,
+    dead: Option<bool>,
+    parent: Option<i64>,
+    poll: Option<i64>,
+    url: Option<&'a str>,
+    score: Option<i64>,
+    title: Option<&'a str>,
+    parts: Option<&'a str>,
+    descendants: Option<i64>,
+    story_id: Option<i64>,
+    depth: Option<i32>,
+}
+
+impl<'a> From<&'a Item> for NewItem<'a> {
+    fn from(item: &'a Item) -> Self {
+        Self {
+            id: item.id,
+            deleted: item.deleted,
+            type_: item.type_.as_deref(),
+            by: item.by.as_deref(),
+            time: item.time,
+            text: item.text.as_deref(),
+            dead: item.dead,
+            parent: item.parent,
+            poll: item.poll,
+            url: item.url.as_deref(),
+            score: item.score,
+            title: item.title.as_deref(),
+            parts: item.parts.as_deref(),
+            descendants: item.descendants,
+            story_id: item.story_id,
+            depth: item.depth,
+        }
+    }
+}
+
+/// Unix seconds of the first whole second at or after `at`, since `time` has no fraction
+fn ceil_timestamp(at: DateTime<Utc>) -> i64 {
+    at.timestamp() + i64::from(at.timestamp_subsec_nanos() > 0)
+}
+
+/// `ids` as a JSON array, for `json_each`
+fn json_ids(ids: &[i64]) -> String {
+    let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+    format!("[{}]", ids.join(","))
+}
+
+fn load_items(conn: &mut SqliteConnection, ids: &[i64]) -> QueryResult<Vec<Item>> {
+    Ok(items::table
+        .filter(items::id.eq_any(ids))
+        .select(ITEM_COLUMNS)
+        .order(items::id)
+        .load::<ItemRow>(conn)?
+        .into_iter()
+        .map(item_from_row)
+        .collect())
+}
+
+fn load_kids(conn: &mut SqliteConnection, ids: &[i64]) -> QueryResult<Vec<Kid>> {
+    kids::table
+        .select((kids::item, kids::kid, kids::display_order))
+        .filter(kids::item.eq_any(ids))
+        .load(conn)
+}
+
+fn resolve_threads(conn: &mut SqliteConnection, ids: &[i64]) -> QueryResult<(usize, usize)> {
+    let story_ids = sql_query(RESOLVE_STORY_IDS)
+        .bind::<Text, _>(json_ids(ids))
+        .execute(conn)?;
+    let paths = sql_query(RESOLVE_PATHS)
+        .bind::<Text, _>(json_ids(ids))
+        .execute(conn)?;
+    Ok((story_ids, paths))
+}
+
+/**
+SQLite storage in a single file, for a personal mirror without a database server.
+
+All calls share one connection on the blocking thread pool, so writes are serialized. There's
+no full-text search, partitioning or change feed.
+*/
+#[derive(Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<SqliteConnection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the file in `db_url`, creating it if it doesn't exist
+    pub fn open(db_url: &str) -> Result<Self, StorageError> {
+        let mut conn = SqliteConnection::establish(sqlite_path(db_url))?;
+        // WAL lets `sqlite3` and backups read the file while the mirror writes to it
+        conn.batch_execute(
+            "PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL; PRAGMA busy_timeout = 5000;",
+        )?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the connection, on the blocking thread pool
+    async fn run<T, F>(&self, f: F) -> Result<T, StorageError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SqliteConnection) -> Result<T, StorageError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn get_items(&self, ids: &[i64]) -> Result<Vec<Item>, StorageError> {
+        let ids = ids.to_vec();
+        self.run(move |conn| Ok(load_items(conn, &ids)?)).await
+    }
+
+    async fn get_kids(&self, ids: &[i64]) -> Result<Vec<Kid>, StorageError> {
+        let ids = ids.to_vec();
+        self.run(move |conn| Ok(load_kids(conn, &ids)?)).await
+    }
+
+    async fn children(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        self.run(move |conn| {
+            Ok(kids::table
+                .inner_join(items::table.on(items::id.eq(kids::kid)))
+                .filter(kids::item.eq(id))
+                .order(kids::display_order)
+                .select(ITEM_COLUMNS)
+                .load::<ItemRow>(conn)?
+                .into_iter()
+                .map(item_from_row)
+                .collect())
+        })
+        .await
+    }
+
+    async fn thread(&self, id: i64) -> Result<Vec<Item>, StorageError> {
+        self.run(move |conn| {
+            let root: Option<Option<String>> = items::table
+                .find(id)
+                .select(items::path)
+                .first(conn)
+                .optional()?;
+            let Some(Some(root)) = root else {
+                return Ok(Vec::new());
+            };
+            Ok(items::table
+                .filter(
+                    items::path.eq(&root).or(items::path
+                        .gt(format!("{}.", root))
+                        .and(items::path.lt(format!("{}/", root)))),
+                )
+                .select(ITEM_COLUMNS)
+                .order(items::path)
+                .load::<ItemRow>(conn)?
+                .into_iter()
+                .map(item_from_row)
+                .collect())
+        })
+        .await
+    }
+
+    async fn stories_by_user(
+        &self,
+        by: &str,
+        before: Option<DateTime<Utc>>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError> {
+        let by = by.to_string();
+        self.run(move |conn| {
+            let mut query = items::table
+                .filter(items::by.eq(by))
+                .filter(items::type_.eq("story"))
+                .select(ITEM_COLUMNS)
+                .order(items::time.desc())
+                .limit(limit)
+                .into_boxed();
+            if let Some(before) = before {
+                query = query.filter(items::time.lt(ceil_timestamp(before)));
+            }
+            Ok(query
+                .load::<ItemRow>(conn)?
+                .into_iter()
+                .map(item_from_row)
+                .collect())
+        })
+        .await
+    }
+
+    async fn items_in_time_range(
+        &self,
+        range: Range<DateTime<Utc>>,
+        kind: Option<&str>,
+        limit: Option<i64>,
+    ) -> Result<Vec<Item>, StorageError> {
+        let kind = kind.map(str::to_string);
+        self.run(move |conn| {
+            let mut query = items::table
+                .filter(items::time.ge(ceil_timestamp(range.start)))
+                .filter(items::time.lt(ceil_timestamp(range.end)))
+                .select(ITEM_COLUMNS)
+                .order(items::time)
+                .into_boxed();
+            if let Some(kind) = kind {
+                query = query.filter(items::type_.eq(kind));
+            }
+            if let Some(limit) = limit {
+                query = query.limit(limit);
+            }
+            Ok(query
+                .load::<ItemRow>(conn)?
+                .into_iter()
+                .map(item_from_row)
+                .collect())
+        })
+        .await
+    }
+
+    async fn search(&self, _query: &SearchQuery) -> Result<Vec<SearchHit>, StorageError> {
+        Err(StorageError::Unsupported("Full-text search"))
+    }
+
+    async fn max_id(&self, ids: Option<RangeInclusive<i64>>) -> Result<Option<i64>, StorageError> {
+        let ids = ids.unwrap_or(i64::MIN..=i64::MAX);
+        self.run(move |conn| {
+            Ok(items::table
+                .select(diesel::dsl::max(items::id))
+                .filter(items::id.between(*ids.start(), *ids.end()))
+                .first(conn)?)
+        })
+        .await
+    }
+
+    async fn existing_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError> {
+        self.run(move |conn| {
+            Ok(items::table
+                .select(items::id)
+                .filter(items::id.between(*ids.start(), *ids.end()))
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn scan_items(
+        &self,
+        ids: RangeInclusive<i64>,
+        limit: i64,
+    ) -> Result<Vec<Item>, StorageError> {
+        self.run(move |conn| {
+            Ok(items::table
+                .filter(items::id.between(*ids.start(), *ids.end()))
+                .select(ITEM_COLUMNS)
+                .order(items::id)
+                .limit(limit)
+                .load::<ItemRow>(conn)?
+                .into_iter()
+                .map(item_from_row)
+                .collect())
+        })
+        .await
+    }
+
+    async fn create_item(&self, item: &Item) -> Result<usize, StorageError> {
+        let item = item.clone();
+        self.run(move |conn| {
+            Ok(insert_into(items::table)
+                .values(NewItem::from(&item))
+                .execute(conn)?)
+        })
+        .await
+    }
+
+    async fn delete_item(&self, id: i64) -> Result<usize, StorageError> {
+        self.run(move |conn| Ok(delete(items::table.find(id)).execute(conn)?))
+            .await
+    }
+
+    async fn get_users(&self, ids: &[&str]) -> Result<Vec<User>, StorageError> {
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        self.run(move |conn| {
+            Ok(users::table
+                .filter(users::id.eq_any(ids))
+                .select(User::as_select())
+                .order(users::id)
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn scan_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, StorageError> {
+        let after = after.map(str::to_string);
+        self.run(move |conn| {
+            let mut query = users::table
+                .select(User::as_select())
+                .order(users::id)
+                .limit(limit)
+                .into_boxed();
+            if let Some(after) = after {
+                query = query.filter(users::id.gt(after));
+            }
+            Ok(query.load(conn)?)
+        })
+        .await
+    }
+
+    async fn upsert_user(&self, user: &User) -> Result<usize, StorageError> {
+        let user = user.clone();
+        self.run(move |conn| {
+            conn.immediate_transaction(|conn| {
+                let upserted = insert_into(users::table)
+                    .values(&user)
+                    .on_conflict(users::id)
+                    .do_update()
+                    .set(&user)
+                    .execute(conn)?;
+                insert_into(changed_users::table)
+                    .values((
+                        changed_users::day.eq(Utc::now().date_naive()),
+                        changed_users::id.eq(&user.id),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                Ok(upserted)
+            })
+        })
+        .await
+    }
+
+    async fn delete_user(&self, id: &str) -> Result<usize, StorageError> {
+        let id = id.to_string();
+        self.run(move |conn| Ok(delete(users::table.find(id)).execute(conn)?))
+            .await
+    }
+
+    async fn upsert_items(
+        &self,
+        items_batch: &[Item],
+        kids_batch: &[Kid],
+    ) -> Result<Vec<ItemChanged>, StorageError> {
+        let (items_batch, kids_batch) = (items_batch.to_vec(), kids_batch.to_vec());
+        self.run(move |conn| {
+            let item_ids: Vec<i64> = items_batch.iter().map(|item| item.id).collect();
+            let changed = conn.immediate_transaction::<_, StorageError, _>(|conn| {
+                let existing: HashMap<i64, Item> = load_items(conn, &item_ids)?
+                    .into_iter()
+                    .map(|item| (item.id, item))
+                    .collect();
+
+                // SQLite can't batch an upsert, but row by row in one transaction is cheap
+                for item in &items_batch {
+                    insert_into(items::table)
+                        .values(NewItem::from(item))
+                        .on_conflict(items::id)
+                        .do_update()
+                        .set((
+                            items::deleted.eq(excluded(items::deleted)),
+                            items::type_.eq(excluded(items::type_)),
+                            items::by.eq(excluded(items::by)),
+                            items::time.eq(excluded(items::time)),
+                            items::text.eq(excluded(items::text)),
+                            items::dead.eq(excluded(items::dead)),
+                            items::parent.eq(excluded(items::parent)),
+                            items::poll.eq(excluded(items::poll)),
+                            items::url.eq(excluded(items::url)),
+                            items::score.eq(excluded(items::score)),
+                            items::title.eq(excluded(items::title)),
+                            items::parts.eq(excluded(items::parts)),
+                            items::descendants.eq(excluded(items::descendants)),
+                        ))
+                        .execute(conn)?;
+                }
+
+                let removed = detached_kids(load_kids(conn, &item_ids)?, &kids_batch);
+                let removed_ids: Vec<i64> = removed.values().flatten().copied().collect();
+                for (item, removed_kids) in removed {
+                    delete(
+                        kids::table
+                            .filter(kids::item.eq(item))
+                            .filter(kids::kid.eq_any(removed_kids)),
+                    )
+                    .execute(conn)?;
+                }
+                // Detached subtrees no longer have a place in the thread
+                if !removed_ids.is_empty() {
+                    sql_query(CLEAR_PATHS)
+                        .bind::<Text, _>(json_ids(&removed_ids))
+                        .execute(conn)?;
+                }
+
+                for kid in &kids_batch {
+                    insert_into(kids::table)
+                        .values(kid)
+                        .on_conflict((kids::item, kids::kid))
+                        .do_update()
+                        .set(kids::display_order.eq(excluded(kids::display_order)))
+                        .execute(conn)?;
+                }
+
+                let (_, changed) = item_changes(&existing, &items_batch);
+                let day = Utc::now().date_naive();
+                for c in &changed {
+                    insert_into(changed_items::table)
+                        .values((changed_items::day.eq(day), changed_items::id.eq(c.id)))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                Ok(changed)
+            })?;
+            resolve_threads(conn, &item_ids)?;
+            Ok(changed)
+        })
+        .await
+    }
+
+    async fn unresolved_thread_ids(
+        &self,
+        ids: RangeInclusive<i64>,
+    ) -> Result<Vec<i64>, StorageError> {
+        self.run(move |conn| {
+            Ok(items::table
+                .select(items::id)
+                .filter(items::id.between(*ids.start(), *ids.end()))
+                .filter(items::story_id.is_null().or(items::path.is_null()))
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn resolve_threads(&self, ids: &[i64]) -> Result<(usize, usize), StorageError> {
+        let ids = ids.to_vec();
+        self.run(move |conn| Ok(resolve_threads(conn, &ids)?)).await
+    }
+
+    async fn empty_item_ids(&self, ids: RangeInclusive<i64>) -> Result<Vec<i64>, StorageError> {
+        self.run(move |conn| {
+            Ok(empty_items::table
+                .select(empty_items::id)
+                .filter(empty_items::id.between(*ids.start(), *ids.end()))
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn save_empty_items(&self, ids: &[i64]) -> Result<(), StorageError> {
+        let ids = ids.to_vec();
+        self.run(move |conn| {
+            conn.immediate_transaction(|conn| {
+                for id in ids {
+                    insert_into(empty_items::table)
+                        .values(empty_items::id.eq(id))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn save_pending_updates(&self, ids: &[i64]) -> Result<(), StorageError> {
+        let ids = ids.to_vec();
+        self.run(move |conn| {
+            conn.immediate_transaction(|conn| {
+                for id in ids {
+                    insert_into(pending_updates::table)
+                        .values(pending_updates::id.eq(id))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn take_pending_updates(&self) -> Result<Vec<i64>, StorageError> {
+        self.run(|conn| {
+            conn.immediate_transaction(|conn| {
+                let ids = pending_updates::table
+                    .select(pending_updates::id)
+                    .load(conn)?;
+                delete(pending_updates::table).execute(conn)?;
+                Ok(ids)
+            })
+        })
+        .await
+    }
+
+    async fn changed_item_ids(&self, day: NaiveDate) -> Result<Vec<i64>, StorageError> {
+        self.run(move |conn| {
+            Ok(changed_items::table
+                .select(changed_items::id)
+                .filter(changed_items::day.eq(day))
+                .order(changed_items::id)
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn changed_user_ids(&self, day: NaiveDate) -> Result<Vec<String>, StorageError> {
+        self.run(move |conn| {
+            Ok(changed_users::table
+                .select(changed_users::id)
+                .filter(changed_users::day.eq(day))
+                .order(changed_users::id)
+                .load(conn)?)
+        })
+        .await
+    }
+
+    async fn prune_changes(&self, before: NaiveDate) -> Result<usize, StorageError> {
+        self.run(move |conn| {
+            conn.immediate_transaction(|conn| {
+                let items = delete(changed_items::table.filter(changed_items::day.lt(before)))
+                    .execute(conn)?;
+                let users = delete(changed_users::table.filter(changed_users::day.lt(before)))
+                    .execute(conn)?;
+                Ok(items + users)
+            })
+        })
+        .await
+    }
+
+    /// Nothing to create: SQLite tables aren't partitioned
+    async fn ensure_partitions(&self, _max_id: i64) -> Result<Vec<String>, StorageError> {
+        Ok(Vec::new())
+    }
+}
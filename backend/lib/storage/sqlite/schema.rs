@@ -0,0 +1,29 @@
+// `items` as created by `migrations_sqlite/`. The other tables match Postgres, so they're
+// shared with `crate::db::schema`.
+
+use crate::db::schema::kids;
+
+diesel::table! {
+    items (id) {
+        id -> Int8,
+        deleted -> Nullable<Bool>,
+        #[sql_name = "type"]
+        type_ -> Nullable<Text>,
+        by -> Nullable<Text>,
+        time -> Nullable<Int8>,
+        text -> Nullable<Text>,
+        dead -> Nullable<Bool>,
+        parent -> Nullable<Int8>,
+        poll -> Nullable<Int8>,
+        url -> Nullable<Text>,
+        score -> Nullable<Int8>,
+        title -> Nullable<Text>,
+        parts -> Nullable<Text>,
+        descendants -> Nullable<Int8>,
+        story_id -> Nullable<Int8>,
+        depth -> Nullable<Int4>,
+        path -> Nullable<Text>,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(items, kids);
@@ -0,0 +1,236 @@
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+
+use super::{diff, download_item, unix_now, DiffStatus, Error, SyncService};
+use crate::db::models;
+use crate::firebase_listener::FirebaseListener;
+
+/// Concurrent HN requests while re-fetching the sample
+const AUDIT_CONCURRENCY: usize = 32;
+
+/// Upper bounds of the item age buckets, in seconds, and their labels
+const AGE_BUCKETS: [(i64, &str); 5] = [
+    (60 * 60 * 24, "<1d"),
+    (60 * 60 * 24 * 7, "1d-7d"),
+    (60 * 60 * 24 * 30, "7d-30d"),
+    (60 * 60 * 24 * 365, "30d-1y"),
+    (i64::MAX, ">1y"),
+];
+
+/// Drift counts for one item age bucket
+#[derive(Serialize, Debug, Default)]
+pub struct BucketStats {
+    /// Sampled items HN returned
+    pub checked: usize,
+    /// On HN but not in the DB
+    pub missing: usize,
+    /// In the DB, but at least one column or kids row differs
+    pub drifted: usize,
+    /// Drifted items per column. `kids` counts items whose kids rows differ
+    pub fields: BTreeMap<&'static str, usize>,
+}
+
+/// Result of `SyncService::audit`
+#[derive(Serialize, Debug, Default)]
+pub struct AuditReport {
+    pub sampled: usize,
+    /// Sampled ids HN couldn't return, e.g. because they were never assigned
+    pub fetch_errors: usize,
+    pub total: BucketStats,
+    pub by_age: BTreeMap<&'static str, BucketStats>,
+}
+
+impl BucketStats {
+    fn record(&mut self, status: Option<DiffStatus>, fields: &[&'static str]) {
+        self.checked += 1;
+        match status {
+            Some(DiffStatus::New) => self.missing += 1,
+            Some(DiffStatus::Changed) => {
+                self.drifted += 1;
+                for field in fields {
+                    *self.fields.entry(field).or_default() += 1;
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn age_bucket(time: Option<i64>, now: i64) -> &'static str {
+    match time {
+        Some(time) => AGE_BUCKETS
+            .iter()
+            .find(|(max_age, _)| now - time < *max_age)
+            .map_or(">1y", |(_, label)| label),
+        None => "unknown",
+    }
+}
+
+impl SyncService {
+    /**
+    `audit` re-fetches a random sample of ids from HN and compares them with the DB, column by column
+    and kids row by kids row.
+
+    The id space up to the max DB item is split into `strata` equal ranges, with an equal share of
+    `sample_size` ids drawn uniformly from each, so old and recent history are both covered.
+    `ids` narrows the id space, e.g. to a single partition. Nothing is written.
+    */
+    pub async fn audit(
+        &self,
+        sample_size: usize,
+        strata: usize,
+        ids: Option<RangeInclusive<i64>>,
+    ) -> Result<AuditReport, Error> {
+        if sample_size == 0 || strata == 0 {
+            return Err(Error::ConfigError(
+                "Audit sample size and strata must be positive".into(),
+            ));
+        }
+        let ids = ids.unwrap_or(1..=i64::MAX);
+        let max_db_item = self
+            .storage
+            .max_id(Some(ids.clone()))
+            .await?
+            .ok_or(Error::ConnectError("Cannot find max DB item!".into()))?;
+
+        let sample = stratified_sample(*ids.start(), max_db_item, sample_size, strata);
+        info!(
+            "Auditing {} ids across {} strata from {} to {}",
+            sample.len(),
+            strata,
+            ids.start(),
+            max_db_item
+        );
+
+        let fb = FirebaseListener::new(self.firebase_url.clone())?;
+        let fetched: Vec<_> = stream::iter(sample.iter().copied())
+            .map(|id| {
+                let fb = &fb;
+                async move {
+                    let mut items_batch: Vec<models::Item> = Vec::new();
+                    let mut kids_batch: Vec<models::Kid> = Vec::new();
+                    match download_item(fb, id, &mut items_batch, &mut kids_batch).await {
+                        Ok(()) => Some((items_batch, kids_batch)),
+                        Err(err) => {
+                            warn!("Could not fetch sampled item {}: {}", id, err);
+                            None
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(AUDIT_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut report = AuditReport {
+            sampled: sample.len(),
+            ..Default::default()
+        };
+        let mut items_batch = Vec::new();
+        let mut kids_batch = Vec::new();
+        for result in fetched {
+            match result {
+                Some((items, kids)) => {
+                    items_batch.extend(items);
+                    kids_batch.extend(kids);
+                }
+                None => report.fetch_errors += 1,
+            }
+        }
+
+        let diffs = diff::diff_items(self.storage.as_ref(), &items_batch, &kids_batch).await?;
+        let diffs: BTreeMap<i64, diff::ItemDiff> = diffs.into_iter().map(|d| (d.id, d)).collect();
+        let now = unix_now();
+        for item in &items_batch {
+            let bucket = age_bucket(item.time, now);
+            let (status, fields) = match diffs.get(&item.id) {
+                Some(diff) => {
+                    let mut fields: Vec<&'static str> = diff.fields.keys().copied().collect();
+                    if diff.kids_changed() {
+                        fields.push("kids");
+                    }
+                    (Some(diff.status), fields)
+                }
+                None => (None, vec![]),
+            };
+            report.total.record(status, &fields);
+            report
+                .by_age
+                .entry(bucket)
+                .or_default()
+                .record(status, &fields);
+        }
+        info!(
+            "Audit done: {} checked, {} missing, {} drifted",
+            report.total.checked, report.total.missing, report.total.drifted
+        );
+        Ok(report)
+    }
+}
+
+/// Draws about `sample_size` distinct ids from `[min_id, max_id]`, evenly split across `strata` ranges
+fn stratified_sample(min_id: i64, max_id: i64, sample_size: usize, strata: usize) -> BTreeSet<i64> {
+    let mut rng = rand::thread_rng();
+    let stratum_len = ((max_id - min_id + 1) / strata as i64).max(1);
+    let per_stratum = (sample_size / strata).max(1);
+    let mut sample = BTreeSet::new();
+    for stratum in 0..strata as i64 {
+        let start = min_id + stratum * stratum_len;
+        if start > max_id {
+            break;
+        }
+        let end = if stratum == strata as i64 - 1 {
+            max_id
+        } else {
+            (start + stratum_len - 1).min(max_id)
+        };
+        for _ in 0..per_stratum {
+            sample.insert(rng.gen_range(start..=end));
+        }
+    }
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stratified_sample;
+
+    #[test]
+    fn stratified_sample_draws_from_every_stratum() {
+        let sample = stratified_sample(1, 1_000_000, 100, 10);
+        assert!(!sample.is_empty() && sample.len() <= 100);
+        assert!(sample.iter().all(|id| (1..=1_000_000).contains(id)));
+        for stratum in 0..10 {
+            let range = 1 + stratum * 100_000..=(stratum + 1) * 100_000;
+            assert!(
+                sample.iter().any(|id| range.contains(id)),
+                "no ids in {:?}",
+                range
+            );
+        }
+    }
+
+    #[test]
+    fn stratified_sample_stays_in_a_range_smaller_than_the_strata() {
+        let sample = stratified_sample(5, 7, 50, 10);
+        assert!(!sample.is_empty());
+        assert!(sample.iter().all(|id| (5..=7).contains(id)));
+    }
+
+    #[test]
+    fn stratified_sample_last_stratum_reaches_max_id() {
+        // 10 ids in 3 strata of 3: the last one also covers the leftover id 10
+        for _ in 0..100 {
+            let sample = stratified_sample(1, 10, 30, 3);
+            assert!(sample.iter().all(|id| (1..=10).contains(id)));
+            if sample.contains(&10) {
+                return;
+            }
+        }
+        panic!("id 10 was never drawn");
+    }
+}
@@ -0,0 +1,128 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use super::Error;
+use crate::db::models;
+use crate::storage::Storage;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    /// Not in the DB yet
+    New,
+    /// In the DB, but some columns or kids differ
+    Changed,
+}
+
+#[derive(Serialize, Debug)]
+pub struct FieldChange {
+    pub old: Value,
+    pub new: Value,
+}
+
+/// How a fetched item differs from its row in the DB
+#[derive(Serialize, Debug)]
+pub struct ItemDiff {
+    pub id: i64,
+    pub status: DiffStatus,
+    /// Changed columns. For new items, every column that has a value
+    pub fields: BTreeMap<&'static str, FieldChange>,
+    pub kids_added: Vec<i64>,
+    pub kids_removed: Vec<i64>,
+    /// Kids in both, but at a different `display_order`
+    pub kids_reordered: Vec<i64>,
+}
+
+impl ItemDiff {
+    pub fn kids_changed(&self) -> bool {
+        !self.kids_added.is_empty()
+            || !self.kids_removed.is_empty()
+            || !self.kids_reordered.is_empty()
+    }
+}
+
+/// Compares fetched items and their kids against the DB. Items that match exactly are left out
+pub(crate) async fn diff_items(
+    storage: &dyn Storage,
+    items_batch: &[models::Item],
+    kids_batch: &[models::Kid],
+) -> Result<Vec<ItemDiff>, Error> {
+    let item_ids: Vec<i64> = items_batch.iter().map(|item| item.id).collect();
+    let stored_items: HashMap<i64, models::Item> = storage
+        .get_items(&item_ids)
+        .await?
+        .into_iter()
+        .map(|item| (item.id, item))
+        .collect();
+    let mut stored_kids: HashMap<i64, HashMap<i64, Option<i64>>> = HashMap::new();
+    for kid in storage.get_kids(&item_ids).await? {
+        stored_kids
+            .entry(kid.item)
+            .or_default()
+            .insert(kid.kid, kid.display_order);
+    }
+    let mut fetched_kids: HashMap<i64, HashMap<i64, Option<i64>>> = HashMap::new();
+    for kid in kids_batch {
+        fetched_kids
+            .entry(kid.item)
+            .or_default()
+            .insert(kid.kid, kid.display_order);
+    }
+
+    let mut diffs = Vec::new();
+    for item in items_batch {
+        let (status, old) = match stored_items.get(&item.id) {
+            Some(old) => (DiffStatus::Changed, old),
+            None => (DiffStatus::New, &models::Item::default()),
+        };
+        let old_values = serde_json::to_value(old)?;
+        let new_values = serde_json::to_value(item)?;
+        let fields: BTreeMap<&'static str, FieldChange> = item
+            .changed_fields(old)
+            .into_iter()
+            .map(|field| {
+                let change = FieldChange {
+                    old: old_values[field].clone(),
+                    new: new_values[field].clone(),
+                };
+                (field, change)
+            })
+            .collect();
+
+        let empty = HashMap::new();
+        let stored = stored_kids.get(&item.id).unwrap_or(&empty);
+        let fetched = fetched_kids.get(&item.id).unwrap_or(&empty);
+        let mut kids_added: Vec<i64> = fetched
+            .keys()
+            .filter(|kid| !stored.contains_key(kid))
+            .copied()
+            .collect();
+        let mut kids_removed: Vec<i64> = stored
+            .keys()
+            .filter(|kid| !fetched.contains_key(kid))
+            .copied()
+            .collect();
+        let mut kids_reordered: Vec<i64> = fetched
+            .iter()
+            .filter(|(kid, order)| stored.get(kid).is_some_and(|old| old != *order))
+            .map(|(kid, _)| *kid)
+            .collect();
+        kids_added.sort();
+        kids_removed.sort();
+        kids_reordered.sort();
+
+        let diff = ItemDiff {
+            id: item.id,
+            status,
+            fields,
+            kids_added,
+            kids_removed,
+            kids_reordered,
+        };
+        if status == DiffStatus::New || !diff.fields.is_empty() || diff.kids_changed() {
+            diffs.push(diff);
+        }
+    }
+    Ok(diffs)
+}
@@ -1,20 +1,29 @@
-use diesel::dsl::max;
-use diesel::insert_into;
-use diesel::pg::upsert::excluded;
-use diesel::prelude::*;
-use diesel::result::Error as DieselError;
-use diesel_async::pooled_connection::deadpool::{Pool, PoolError};
-use diesel_async::RunQueryDsl;
+use chrono::{DateTime, Utc};
 use futures::future::join_all;
 use log::{debug, info};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::vec;
 use thiserror::Error;
 use tokio::task::{spawn, JoinError};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+mod audit;
+mod diff;
+mod partitions;
+mod profiles;
+mod supervisor;
+mod threads;
+pub use audit::{AuditReport, BucketStats};
+pub use diff::{DiffStatus, FieldChange, ItemDiff};
+pub use supervisor::WorkerHealth;
 
 use crate::db::models;
-use crate::db::schema::items;
-use crate::db::schema::kids;
+use crate::events::EventBus;
 use crate::firebase_listener::{FirebaseListener, FirebaseListenerErr};
+use crate::storage::{Storage, StorageError};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -25,61 +34,66 @@ pub enum Error {
     FirebaseError(#[from] FirebaseListenerErr),
 
     #[error(transparent)]
-    DieselError(#[from] DieselError),
+    StorageError(#[from] StorageError),
 
     #[error(transparent)]
-    DBPoolError(#[from] PoolError),
+    JsonError(#[from] serde_json::Error),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
 
     #[error("Task join error: {0}")]
     TaskJoinError(#[from] JoinError),
 }
 
+/// Events buffered per subscriber before it starts missing them
+const EVENT_BUS_CAPACITY: usize = 16_384;
+
 pub struct SyncService {
-    /// Pool for Postgres DB backing up HN data
-    db_pool: Pool<diesel_async::AsyncPgConnection>,
+    /// DB backing up HN data
+    storage: Arc<dyn Storage>,
     firebase_url: String,
     num_workers: usize,
+    /// Restart bookkeeping for supervised realtime workers
+    health: Arc<WorkerHealth>,
+    /// Where workers publish the items they write
+    events: EventBus,
+    /// Diff fetched items against the DB instead of writing them
+    dry_run: bool,
 }
 impl SyncService {
     pub fn new(
         firebase_url: String,
-        db_pool: Pool<diesel_async::AsyncPgConnection>,
+        storage: Arc<dyn Storage>,
         num_workers: usize,
+        dry_run: bool,
     ) -> Self {
         Self {
-            db_pool,
+            storage,
             num_workers,
             firebase_url,
+            health: Arc::new(WorkerHealth::new()),
+            events: EventBus::new(EVENT_BUS_CAPACITY),
+            dry_run,
         }
     }
 
-    /// `divide_ranges` somewhat fairly distributes the catchup range among workers
-    fn divide_ranges(&self, min_id: i64, max_id: i64) -> Vec<(i64, i64)> {
-        let coerced_nworkers: i64 = self.num_workers as i64;
-        if min_id >= max_id {
-            vec![]
-        } else if max_id - min_id <= coerced_nworkers {
-            // Potentially can't saturate workers, so give as many as possible an ID
-            return (min_id..=max_id).map(|i| (i, i)).collect();
-        } else {
-            let num_ids_per_worker = (max_id - min_id) / coerced_nworkers;
-
-            let mut res: Vec<(i64, i64)> = (0..coerced_nworkers)
-                .map(|i| {
-                    (
-                        min_id + i * num_ids_per_worker,
-                        min_id + (i + 1) * num_ids_per_worker - 1,
-                    )
-                })
-                .collect();
+    fn sink(&self) -> Sink {
+        Sink {
+            storage: self.storage.clone(),
+            events: self.events.clone(),
+            dry_run: self.dry_run,
+        }
+    }
 
-            // for convenience, give the remainder to the last: it's only on the order of ~20, which compared to thousands of items is nothing
-            if let Some(i) = res.last_mut() {
-                *i = (i.0, max_id);
-            }
+    /// Bus of item changes written by this service's workers
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
 
-            return res;
-        }
+    /// Health of the realtime workers, e.g. for the `/health` endpoint
+    pub fn health(&self) -> Arc<WorkerHealth> {
+        self.health.clone()
     }
 
     /**
@@ -96,19 +110,11 @@ impl SyncService {
         let max_fb_id = fb.get_max_id().await?;
         info!("Current max item on HN: {}", max_fb_id);
 
-        let mut conn = self
-            .db_pool
-            .get()
-            .await
-            .map_err(|_| Error::ConnectError("Listener could not access db pool!".into()))?;
-
-        let max_db_item: Option<i64> = items::dsl::items
-            .select(max(items::dsl::id))
-            .first(&mut conn)
-            .await?;
-        let max_db_item = max_db_item.ok_or(Error::ConnectError(
-            "Cannot find max DB item in Postgres!".into(),
-        ))?;
+        let max_db_item = self
+            .storage
+            .max_id(None)
+            .await?
+            .ok_or(Error::ConnectError("Cannot find max DB item!".into()))?;
         let min_id = match n_start {
             Some(n) => n,
             // TODO: Make this constant less arbitrary
@@ -119,21 +125,22 @@ impl SyncService {
             Some(n) => min_id + n,
             None => max_fb_id,
         };
+        self.ensure_partitions(max_id).await?;
         info!("Current max item in db: {:?}", max_db_item);
-        let id_ranges = self.divide_ranges(min_id, max_id);
+        let id_ranges = divide_ranges(self.num_workers, min_id, max_id);
         info!("Items to download: {}", max_fb_id - max_db_item);
         info!("Ranges: {:?}", &id_ranges);
 
         let mut handles = Vec::new();
         for range in id_ranges.into_iter() {
-            let db_pool = self.db_pool.clone();
+            let sink = self.sink();
             let fb_url = self.firebase_url.clone();
             let handle = spawn(async move {
                 worker(
                     &fb_url,
                     Some(range.0),
                     Some(range.1),
-                    db_pool,
+                    sink,
                     WorkerMode::Catchup,
                     None,
                 )
@@ -158,34 +165,312 @@ impl SyncService {
         Ok(())
     }
 
-    /// Realtime subscription to HN item updates
+    /**
+    Realtime subscription to HN item updates.
+
+    Workers are supervised: a worker that fails is restarted with backoff, and `health()` turns
+    unhealthy while one keeps failing.
+
+    Runs until the channel closes, or until `shutdown_token` fires. On shutdown, workers drain
+    the remaining queue for up to `drain_deadline`; any ids still queued or in flight after that
+    are persisted to `pending_updates` for the next start to pick up. If `abort_token` fires,
+    workers stop without draining and everything left is persisted right away.
+    */
     pub async fn realtime_update(
         &self,
         num_workers: usize,
         receiver: flume::Receiver<i64>,
+        shutdown_token: CancellationToken,
+        abort_token: CancellationToken,
+        drain_deadline: Duration,
     ) -> Result<(), Error> {
         info!("Spawning {} realtime update workers...", num_workers);
+        let in_flight: InFlight = Arc::new(Mutex::new(HashSet::new()));
         let mut update_worker_handles = Vec::new();
-        for _ in 0..num_workers {
+        for slot in 0..num_workers {
             let worker_receiver = receiver.clone();
             let firebase_url = self.firebase_url.clone();
-            let db_pool = self.db_pool.clone();
+            let sink = self.sink();
+            let worker_in_flight = in_flight.clone();
+            let health = self.health.clone();
             let handle = tokio::spawn(async move {
+                supervisor::supervise(slot, &health, || {
+                    worker(
+                        &firebase_url,
+                        None,
+                        None,
+                        sink.clone(),
+                        WorkerMode::Updater(worker_in_flight.clone()),
+                        Some(worker_receiver.clone()),
+                    )
+                })
+                .await;
+                Ok(())
+            });
+            update_worker_handles.push(handle);
+        }
+        info!("Successfully spawned all realtime update workers.");
+
+        let abort_handles: Vec<_> = update_worker_handles
+            .iter()
+            .map(|h| h.abort_handle())
+            .collect();
+        let mut workers = join_all(update_worker_handles);
+        tokio::select! {
+            results = &mut workers => {
+                log_worker_results(results);
+                return Ok(());
+            }
+            _ = shutdown_token.cancelled() => {}
+        }
+
+        let drained = if abort_token.is_cancelled() {
+            None
+        } else {
+            info!(
+                "Draining realtime queue: {} ids queued, deadline {:?}",
+                receiver.len(),
+                drain_deadline
+            );
+            tokio::select! {
+                results = timeout(drain_deadline, &mut workers) => results.ok(),
+                _ = abort_token.cancelled() => None,
+            }
+        };
+        match drained {
+            Some(results) => {
+                log_worker_results(results);
+                info!("Realtime queue drained.");
+            }
+            None => {
+                // Workers are stopped before the queue is read, so none can take an id from it
+                // afterwards. An id leaves the queue and enters `in_flight` without yielding in
+                // between, so each one is still queued, in flight, or written
+                for handle in abort_handles {
+                    handle.abort();
+                }
+                let _ = workers.await;
+                let mut unprocessed: Vec<i64> = receiver.drain().collect();
+                if let Ok(in_flight) = in_flight.lock() {
+                    unprocessed.extend(in_flight.iter());
+                }
+                log::warn!(
+                    "Realtime workers stopped, persisting {} unprocessed ids",
+                    unprocessed.len()
+                );
+                self.persist_pending_updates(&unprocessed).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves ids to `pending_updates` to be re-queued on the next start
+    async fn persist_pending_updates(&self, ids: &[i64]) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        Ok(self.storage.save_pending_updates(ids).await?)
+    }
+
+    /// Removes and returns all ids left in `pending_updates` by a previous shutdown
+    pub async fn take_pending_updates(&self) -> Result<Vec<i64>, Error> {
+        if self.dry_run {
+            return Ok(vec![]);
+        }
+        Ok(self.storage.take_pending_updates().await?)
+    }
+
+    /**
+    `backfill` walks `[min_id, max_id]` in chunks of `chunk_size` and downloads every item missing from the DB.
+
+    Defaults to the whole history, from item 1 to the current max item on HN.
+    Ids already in the DB, or recorded in `empty_items` because HN answered `null` for them,
+    are skipped, so an interrupted backfill can simply be re-run.
+    */
+    pub async fn backfill(
+        &self,
+        min_id: Option<i64>,
+        max_id: Option<i64>,
+        chunk_size: i64,
+    ) -> Result<(), Error> {
+        if chunk_size < 1 {
+            return Err(Error::ConfigError(
+                "Backfill chunk size must be positive".into(),
+            ));
+        }
+        let fb = FirebaseListener::new(self.firebase_url.clone())?;
+        let min_id = min_id.unwrap_or(1);
+        let max_id = match max_id {
+            Some(n) => n,
+            None => fb.get_max_id().await?,
+        };
+        info!("Backfilling items {} to {}", min_id, max_id);
+        self.ensure_partitions(max_id).await?;
+
+        // Bounded so the chunk scan doesn't run arbitrarily far ahead of the downloads
+        let (sender, receiver) = flume::bounded::<i64>(chunk_size as usize);
+        let mut handles = Vec::new();
+        for _ in 0..self.num_workers {
+            let worker_receiver = receiver.clone();
+            let firebase_url = self.firebase_url.clone();
+            let sink = self.sink();
+            handles.push(spawn(async move {
                 worker(
                     &firebase_url,
                     None,
                     None,
-                    db_pool,
-                    WorkerMode::Updater,
+                    sink,
+                    WorkerMode::Backfill,
                     Some(worker_receiver),
                 )
                 .await
-            });
-            update_worker_handles.push(handle);
+            }));
+        }
+        drop(receiver);
+
+        for (chunk_start, chunk_end) in backfill_chunks(min_id, max_id, chunk_size) {
+            let missing = self.missing_ids(chunk_start, chunk_end).await?;
+            info!(
+                "Backfill chunk {} to {}: {} items missing",
+                chunk_start,
+                chunk_end,
+                missing.len()
+            );
+            for id in missing {
+                if sender.send_async(id).await.is_err() {
+                    return Err(Error::ConnectError(
+                        "All backfill workers have exited!".into(),
+                    ));
+                }
+            }
+        }
+        // Closing the channel tells workers to flush and exit
+        drop(sender);
+
+        log_worker_results(join_all(handles).await);
+        info!("Backfill of {} to {} complete", min_id, max_id);
+        Ok(())
+    }
+
+    /**
+    `refresh_young_stories` re-fetches each story as its age crosses each checkpoint in `schedule`.
+
+    Stories drop out of `updates.json` long before their score and comment count settle,
+    so this captures them at decaying intervals instead. Every `tick`, a story is due for each checkpoint
+    its age passed since the previous tick; runs until `cancel_token` fires. Errors are logged, not fatal:
+    if listing due stories fails, the next tick covers the missed window too.
+    */
+    pub async fn refresh_young_stories(
+        &self,
+        schedule: Vec<Duration>,
+        tick: Duration,
+        cancel_token: CancellationToken,
+    ) -> Result<(), Error> {
+        let fb = FirebaseListener::new(self.firebase_url.clone())?;
+        let mut items_batch: Vec<models::Item> = Vec::new();
+        let mut kids_batch: Vec<models::Kid> = Vec::new();
+        let mut interval = tokio::time::interval(tick);
+        let mut last_tick = unix_now();
+        info!("Refreshing young stories at ages {:?}", schedule);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel_token.cancelled() => {
+                    info!("Cancellation token triggered, stopping story refresh.");
+                    break;
+                }
+            }
+            let now = unix_now();
+            let due = match self.stories_due(&schedule, last_tick, now).await {
+                Ok(due) => due,
+                Err(err) => {
+                    log::error!("Could not list stories due for refresh: {}", err);
+                    continue;
+                }
+            };
+            last_tick = now;
+            if due.is_empty() {
+                continue;
+            }
+
+            debug!("Refreshing {} stories", due.len());
+            for id in due {
+                if let Err(err) = download_item(&fb, id, &mut items_batch, &mut kids_batch).await {
+                    log::warn!("Could not refresh story {}: {}", id, err);
+                }
+            }
+            // Through the sink, so a dry run diffs the refreshed stories instead of writing them
+            if let Err(err) = self.sink().flush(&mut items_batch, &mut kids_batch).await {
+                log::error!("Could not write refreshed stories: {}", err);
+                items_batch.clear();
+                kids_batch.clear();
+            }
         }
-        info!("Successfully spawned all realtime update workers.");
         Ok(())
     }
+
+    /// Stories whose age crossed a checkpoint in `schedule` between `since` and `until` (unix seconds)
+    async fn stories_due(
+        &self,
+        schedule: &[Duration],
+        since: i64,
+        until: i64,
+    ) -> Result<BTreeSet<i64>, Error> {
+        let mut due = BTreeSet::new();
+        for checkpoint in schedule {
+            // Config caps checkpoints at `i64::MAX` seconds, but library callers may pass any
+            let Ok(age) = i64::try_from(checkpoint.as_secs()) else {
+                continue;
+            };
+            // Created in (since - age, until - age]. Times are whole seconds
+            let (Some(from), Some(to)) = (
+                DateTime::<Utc>::from_timestamp(since - age + 1, 0),
+                DateTime::<Utc>::from_timestamp(until - age + 1, 0),
+            ) else {
+                continue;
+            };
+            let ids = self
+                .storage
+                .items_in_time_range(from..to, Some("story"), None)
+                .await?
+                .into_iter()
+                .map(|story| story.id);
+            due.extend(ids);
+        }
+        Ok(due)
+    }
+
+    /// Ids in `[min_id, max_id]` that are neither in the items table nor known to be empty
+    async fn missing_ids(&self, min_id: i64, max_id: i64) -> Result<Vec<i64>, Error> {
+        let mut present: HashSet<i64> = self
+            .storage
+            .existing_ids(min_id..=max_id)
+            .await?
+            .into_iter()
+            .collect();
+        present.extend(self.storage.empty_item_ids(min_id..=max_id).await?);
+        Ok((min_id..=max_id)
+            .filter(|id| !present.contains(id))
+            .collect())
+    }
+}
+
+fn log_worker_results(results: Vec<Result<Result<(), Error>, JoinError>>) {
+    for result in results {
+        match result {
+            Ok(Ok(_)) => debug!("Worker finished"),
+            Ok(Err(err)) => log::error!("A worker failed: {:?}", err),
+            Err(err) => log::error!("A worker panicked: {:?}", err),
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
 }
 
 async fn download_item(
@@ -198,7 +483,7 @@ async fn download_item(
     if let Some(kids) = &raw_item.kids {
         for (idx, kid) in kids.iter().enumerate() {
             kids_batch.push(models::Kid {
-                item: *&raw_item.id,
+                item: raw_item.id,
                 kid: *kid,
                 display_order: Some(idx as i64),
             })
@@ -209,63 +494,131 @@ async fn download_item(
     Ok(())
 }
 
+/// Writes a batch through `storage`, then publishes the items whose columns changed on `events`
 async fn upload_items(
-    pool: &Pool<diesel_async::AsyncPgConnection>,
+    storage: &dyn Storage,
     items_batch: &mut Vec<models::Item>,
     kids_batch: &mut Vec<models::Kid>,
+    events: &EventBus,
 ) -> Result<(), Error> {
-    let mut conn = pool.get().await?;
-    insert_into(items::dsl::items)
-        .values(&*items_batch)
-        .on_conflict(items::id)
-        .do_update()
-        .set((
-            items::deleted.eq(excluded(items::deleted)),
-            items::type_.eq(excluded(items::type_)),
-            items::by.eq(excluded(items::by)),
-            items::time.eq(excluded(items::time)),
-            items::text.eq(excluded(items::text)),
-            items::dead.eq(excluded(items::dead)),
-            items::parent.eq(excluded(items::parent)),
-            items::poll.eq(excluded(items::poll)),
-            items::url.eq(excluded(items::url)),
-            items::score.eq(excluded(items::score)),
-            items::title.eq(excluded(items::title)),
-            items::parts.eq(excluded(items::parts)),
-            items::descendants.eq(excluded(items::descendants)),
-        ))
-        .execute(&mut conn)
-        .await?;
+    if items_batch.is_empty() {
+        return Ok(());
+    }
+    let changed = storage.upsert_items(items_batch, kids_batch).await?;
+    for event in changed {
+        events.publish(event);
+    }
     items_batch.clear();
-
-    insert_into(kids::dsl::kids)
-        .values(&*kids_batch)
-        .on_conflict((kids::item, kids::kid))
-        .do_update()
-        .set(kids::display_order.eq(excluded(kids::display_order)))
-        .execute(&mut conn)
-        .await?;
     kids_batch.clear();
     Ok(())
 }
 
+/// Where workers send downloaded batches: upserted into the DB, or in dry-run mode, diffed against it
+#[derive(Clone)]
+struct Sink {
+    storage: Arc<dyn Storage>,
+    events: EventBus,
+    dry_run: bool,
+}
+
+impl Sink {
+    /// Writes or diffs the batches, then clears them
+    async fn flush(
+        &self,
+        items_batch: &mut Vec<models::Item>,
+        kids_batch: &mut Vec<models::Kid>,
+    ) -> Result<(), Error> {
+        if !self.dry_run {
+            return upload_items(self.storage.as_ref(), items_batch, kids_batch, &self.events)
+                .await;
+        }
+        let diffs = diff::diff_items(self.storage.as_ref(), items_batch, kids_batch).await?;
+        info!(
+            "Dry run: {} of {} items differ from the DB",
+            diffs.len(),
+            items_batch.len()
+        );
+        // One JSON object per line on stdout; logs go to stderr
+        for diff in diffs {
+            println!("{}", serde_json::to_string(&diff)?);
+        }
+        items_batch.clear();
+        kids_batch.clear();
+        Ok(())
+    }
+
+    /// Records ids HN answered `null` for, then clears them. Nothing is written in dry-run mode
+    async fn flush_empty(&self, empty_ids: &mut Vec<i64>) -> Result<(), Error> {
+        if !self.dry_run && !empty_ids.is_empty() {
+            self.storage.save_empty_items(empty_ids).await?;
+        }
+        empty_ids.clear();
+        Ok(())
+    }
+}
+
+/// `[min_id, max_id]` in consecutive chunks of `chunk_size` ids, the last one possibly shorter
+fn backfill_chunks(min_id: i64, max_id: i64, chunk_size: i64) -> Vec<(i64, i64)> {
+    let mut chunks = Vec::new();
+    let mut chunk_start = min_id;
+    while chunk_start <= max_id {
+        let chunk_end = (chunk_start + chunk_size - 1).min(max_id);
+        chunks.push((chunk_start, chunk_end));
+        chunk_start = chunk_end + 1;
+    }
+    chunks
+}
+
+/// `divide_ranges` somewhat fairly distributes the catchup range among `num_workers` workers
+fn divide_ranges(num_workers: usize, min_id: i64, max_id: i64) -> Vec<(i64, i64)> {
+    let coerced_nworkers: i64 = num_workers as i64;
+    if min_id >= max_id {
+        vec![]
+    } else if max_id - min_id <= coerced_nworkers {
+        // Potentially can't saturate workers, so give as many as possible an ID
+        (min_id..=max_id).map(|i| (i, i)).collect()
+    } else {
+        let num_ids_per_worker = (max_id - min_id) / coerced_nworkers;
+
+        let mut res: Vec<(i64, i64)> = (0..coerced_nworkers)
+            .map(|i| {
+                (
+                    min_id + i * num_ids_per_worker,
+                    min_id + (i + 1) * num_ids_per_worker - 1,
+                )
+            })
+            .collect();
+
+        // for convenience, give the remainder to the last: it's only on the order of ~20, which compared to thousands of items is nothing
+        if let Some(i) = res.last_mut() {
+            *i = (i.0, max_id);
+        }
+
+        res
+    }
+}
+
+/// Ids an updater worker has received but not yet written
+type InFlight = Arc<Mutex<HashSet<i64>>>;
+
 enum WorkerMode {
     Catchup,
-    Updater,
+    Updater(InFlight),
+    /// Like `Updater`, but batches uploads and skips items that fail to download
+    Backfill,
 }
 
 async fn worker(
     firebase_url: &str,
     min_id: Option<i64>,
     max_id: Option<i64>,
-    pool: Pool<diesel_async::AsyncPgConnection>,
+    sink: Sink,
     mode: WorkerMode,
     receiver: Option<flume::Receiver<i64>>,
 ) -> Result<(), Error> {
     // TODO: Magic number, fix this
     const FLUSH_INTERVAL: usize = 1000;
-    let fb = FirebaseListener::new(firebase_url.to_string())
-        .map_err(|_| Error::ConnectError("HALP".into()))?;
+    let fb = FirebaseListener::new(firebase_url.to_string())?;
 
     let mut items_batch: Vec<models::Item> = Vec::new();
     let mut kids_batch: Vec<models::Kid> = Vec::new();
@@ -277,19 +630,126 @@ async fn worker(
                     download_item(&fb, i, &mut items_batch, &mut kids_batch).await?;
                     if items_batch.len() == FLUSH_INTERVAL || i == max_id {
                         info!("Pushing {} to {}", (i - items_batch.len() as i64), i);
-                        upload_items(&pool, &mut items_batch, &mut kids_batch).await?;
+                        sink.flush(&mut items_batch, &mut kids_batch).await?;
                     }
                 }
             }
         }
-        WorkerMode::Updater => {
+        WorkerMode::Updater(in_flight) => {
+            let receiver = receiver.ok_or(Error::ConnectError("No channel provided!".into()))?;
+            while let Ok(id) = receiver.recv_async().await {
+                if let Ok(mut in_flight) = in_flight.lock() {
+                    in_flight.insert(id);
+                }
+                let written = async {
+                    download_item(&fb, id, &mut items_batch, &mut kids_batch).await?;
+                    debug!("Pushing {}", id);
+                    sink.flush(&mut items_batch, &mut kids_batch).await
+                }
+                .await;
+                // Removed on failure too, or the id would be persisted and re-queued on every start
+                if let Ok(mut in_flight) = in_flight.lock() {
+                    in_flight.remove(&id);
+                }
+                written?;
+            }
+        }
+        WorkerMode::Backfill => {
             let receiver = receiver.ok_or(Error::ConnectError("No channel provided!".into()))?;
+            let mut empty_ids: Vec<i64> = Vec::new();
             while let Ok(id) = receiver.recv_async().await {
-                download_item(&fb, id, &mut items_batch, &mut kids_batch).await?;
-                debug!("Pushing {}", id);
-                upload_items(&pool, &mut items_batch, &mut kids_batch).await?;
+                match download_item(&fb, id, &mut items_batch, &mut kids_batch).await {
+                    Ok(()) => {}
+                    Err(Error::FirebaseError(FirebaseListenerErr::EmptyItem(_))) => {
+                        empty_ids.push(id)
+                    }
+                    // A failed item is left missing, so the next backfill run retries it
+                    Err(err) => log::warn!("Skipping item {}: {}", id, err),
+                }
+                if items_batch.len() == FLUSH_INTERVAL {
+                    debug!("Pushing {} backfilled items", items_batch.len());
+                    sink.flush(&mut items_batch, &mut kids_batch).await?;
+                }
+                if empty_ids.len() == FLUSH_INTERVAL {
+                    sink.flush_empty(&mut empty_ids).await?;
+                }
             }
+            if !items_batch.is_empty() {
+                sink.flush(&mut items_batch, &mut kids_batch).await?;
+            }
+            sink.flush_empty(&mut empty_ids).await?;
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{backfill_chunks, divide_ranges, SyncService};
+    use crate::db::migrations;
+    use crate::db::models::Item;
+    use crate::storage::{SqliteStorage, Storage};
+    use std::sync::Arc;
+
+    /// A migrated SQLite mirror in a fresh file under the temp dir
+    async fn sqlite_storage(name: &str) -> SqliteStorage {
+        let path = std::env::temp_dir().join(format!("backend-{}-{}.db", name, std::process::id()));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+        let db_url = format!("sqlite://{}", path.display());
+        migrations::run_pending(&db_url).await.unwrap();
+        SqliteStorage::open(&db_url).unwrap()
+    }
+
+    #[test]
+    fn backfill_chunks_cover_the_range_in_order() {
+        assert_eq!(backfill_chunks(1, 10, 4), vec![(1, 4), (5, 8), (9, 10)]);
+        assert_eq!(backfill_chunks(7, 7, 100), vec![(7, 7)]);
+        assert!(backfill_chunks(8, 7, 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_ids_skips_stored_and_empty_items() {
+        let storage = sqlite_storage("missing-ids").await;
+        let items: Vec<Item> = [1, 2, 5]
+            .into_iter()
+            .map(|id| Item {
+                id,
+                ..Default::default()
+            })
+            .collect();
+        storage.upsert_items(&items, &[]).await.unwrap();
+        storage.save_empty_items(&[3]).await.unwrap();
+
+        let service = SyncService::new(String::new(), Arc::new(storage), 1, false);
+        assert_eq!(service.missing_ids(1, 6).await.unwrap(), vec![4, 6]);
+        assert_eq!(service.missing_ids(2, 3).await.unwrap(), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn divide_ranges_covers_the_range_without_gaps() {
+        let ranges = divide_ranges(4, 100, 1_000);
+        assert_eq!(ranges.len(), 4);
+        assert_eq!(ranges.first().unwrap().0, 100);
+        assert_eq!(ranges.last().unwrap().1, 1_000);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].1 + 1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn divide_ranges_gives_the_remainder_to_the_last_worker() {
+        assert_eq!(divide_ranges(3, 0, 10), vec![(0, 2), (3, 5), (6, 10)]);
+    }
+
+    #[test]
+    fn divide_ranges_hands_out_single_ids_when_workers_outnumber_them() {
+        assert_eq!(divide_ranges(8, 5, 8), vec![(5, 5), (6, 6), (7, 7), (8, 8)]);
+    }
+
+    #[test]
+    fn divide_ranges_is_empty_for_an_empty_range() {
+        assert!(divide_ranges(4, 10, 9).is_empty());
+    }
+}
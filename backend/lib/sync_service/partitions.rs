@@ -0,0 +1,55 @@
+use log::{error, info, warn};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use super::{Error, SyncService};
+use crate::firebase_listener::FirebaseListener;
+
+impl SyncService {
+    /// Creates the partitions needed to hold ids up to `max_id`. Skipped in dry runs, which don't write
+    pub(crate) async fn ensure_partitions(&self, max_id: i64) -> Result<(), Error> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let created = self.storage.ensure_partitions(max_id).await?;
+        if !created.is_empty() {
+            info!("Created partitions {:?}", created);
+        }
+        Ok(())
+    }
+
+    /**
+    `extend_partitions` keeps partitions ahead of HN's `maxitem`, checking every `tick`.
+
+    Catchup and backfill create what they need up front; this covers a leader that follows
+    realtime updates for weeks. Runs until `cancel_token` fires; a failed tick is logged and retried
+    on the next one.
+    */
+    pub async fn extend_partitions(
+        &self,
+        tick: Duration,
+        cancel_token: CancellationToken,
+    ) -> Result<(), Error> {
+        let fb = FirebaseListener::new(self.firebase_url.clone())?;
+        let mut interval = tokio::time::interval(tick);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel_token.cancelled() => {
+                    info!("Cancellation token triggered, stopping partition upkeep.");
+                    break;
+                }
+            }
+            // HN or the DB being briefly unreachable is fine: there's a whole partition of headroom
+            match fb.get_max_id().await {
+                Ok(max_id) => {
+                    if let Err(err) = self.ensure_partitions(max_id).await {
+                        error!("Could not create partitions up to {}: {}", max_id, err);
+                    }
+                }
+                Err(err) => warn!("Could not fetch max item for partition upkeep: {}", err),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use log::{debug, info, warn};
+
+use super::{Error, SyncService};
+use crate::db::models;
+use crate::firebase_listener::FirebaseListener;
+
+impl SyncService {
+    /**
+    `update_profiles` fetches each user id HN reports in `updates.json` and upserts it, which also
+    records it in `changed_users` for the daily delta.
+
+    Runs until the channel closes. A profile that fails to fetch or write is logged and skipped:
+    HN reports active users again soon enough. Dry runs fetch profiles but don't write them.
+    */
+    pub async fn update_profiles(&self, receiver: flume::Receiver<String>) -> Result<(), Error> {
+        let fb = FirebaseListener::new(self.firebase_url.clone())?;
+        while let Ok(id) = receiver.recv_async().await {
+            let user = match fb.get_user(&id).await {
+                Ok(Some(user)) => models::User::from(user),
+                Ok(None) => {
+                    debug!("User {} is null, skipping", id);
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Could not fetch user {}: {}", id, err);
+                    continue;
+                }
+            };
+            if self.dry_run {
+                continue;
+            }
+            if let Err(err) = self.storage.upsert_user(&user).await {
+                warn!("Could not write user {}: {}", id, err);
+            }
+        }
+        info!("Profile channel closed, stopping profile updates.");
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use futures::FutureExt;
+use log::{error, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::Error;
+
+/// Backoff before the first restart; doubles on each consecutive failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// A worker that fails this many times in a row marks the service unhealthy
+const UNHEALTHY_AFTER: u32 = 5;
+/// A worker that stays up this long is considered recovered
+const STABLE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Default)]
+struct SlotState {
+    consecutive_failures: u32,
+    last_failure: Option<Instant>,
+}
+
+/// Restart counts and failure streaks of supervised workers, shared with the health endpoint
+#[derive(Default)]
+pub struct WorkerHealth {
+    restarts: AtomicUsize,
+    slots: Mutex<HashMap<usize, SlotState>>,
+}
+
+impl WorkerHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Total worker restarts since startup
+    pub fn restarts(&self) -> usize {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Unhealthy while any worker is in a recent streak of `UNHEALTHY_AFTER` failures
+    pub fn is_healthy(&self) -> bool {
+        let slots = match self.slots.lock() {
+            Ok(slots) => slots,
+            Err(_) => return false,
+        };
+        !slots.values().any(|slot| {
+            slot.consecutive_failures >= UNHEALTHY_AFTER
+                && slot
+                    .last_failure
+                    .is_some_and(|t| t.elapsed() < STABLE_AFTER)
+        })
+    }
+
+    /// Records a failure of worker `slot` and returns its current failure streak
+    fn record_failure(&self, slot: usize, uptime: Duration) -> u32 {
+        let mut slots = match self.slots.lock() {
+            Ok(slots) => slots,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let state = slots.entry(slot).or_default();
+        if uptime >= STABLE_AFTER {
+            state.consecutive_failures = 0;
+        }
+        state.consecutive_failures += 1;
+        state.last_failure = Some(Instant::now());
+        state.consecutive_failures
+    }
+}
+
+/**
+`supervise` runs the worker built by `make_worker` until it returns `Ok`,
+restarting it with exponential backoff whenever it errors or panics.
+*/
+pub(crate) async fn supervise<F, Fut>(slot: usize, health: &WorkerHealth, mut make_worker: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    loop {
+        let started = Instant::now();
+        let err = match AssertUnwindSafe(make_worker()).catch_unwind().await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => format!("{}", err),
+            Err(_) => "worker panicked".to_string(),
+        };
+
+        let failures = health.record_failure(slot, started.elapsed());
+        health.restarts.fetch_add(1, Ordering::Relaxed);
+        let backoff = BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_BACKOFF);
+        if failures >= UNHEALTHY_AFTER {
+            error!(
+                "Worker {} failed {} times in a row: {}. Restarting in {:?}",
+                slot, failures, err, backoff
+            );
+        } else {
+            warn!(
+                "Worker {} failed: {}. Restarting in {:?}",
+                slot, err, backoff
+            );
+        }
+        tokio::time::sleep(backoff).await;
+    }
+}
@@ -0,0 +1,56 @@
+use log::info;
+use std::ops::RangeInclusive;
+
+use super::{Error, SyncService};
+
+impl SyncService {
+    /**
+    `backfill_threads` fills `story_id`, `depth` and `path` for rows ingested before they were maintained.
+
+    Walks the DB in ascending id chunks, so parents are generally resolved before their children.
+    Only rows still missing one of them are touched, so this can be re-run after an interruption.
+    `ids` limits the walk, e.g. to a single partition.
+    */
+    pub async fn backfill_threads(
+        &self,
+        chunk_size: i64,
+        ids: Option<RangeInclusive<i64>>,
+    ) -> Result<(), Error> {
+        if chunk_size < 1 {
+            return Err(Error::ConfigError(
+                "Backfill chunk size must be positive".into(),
+            ));
+        }
+        let Some(max_db_item) = self.storage.max_id(None).await? else {
+            return Ok(());
+        };
+        let (mut chunk_start, max_db_item) = match ids {
+            Some(ids) => (*ids.start(), max_db_item.min(*ids.end())),
+            None => (1, max_db_item),
+        };
+
+        let (mut total_story_ids, mut total_paths) = (0, 0);
+        while chunk_start <= max_db_item {
+            let chunk_end = chunk_start + chunk_size - 1;
+            let ids = self
+                .storage
+                .unresolved_thread_ids(chunk_start..=chunk_end)
+                .await?;
+            if !ids.is_empty() {
+                let (story_ids, paths) = self.storage.resolve_threads(&ids).await?;
+                info!(
+                    "Resolved {} story ids and {} paths in {} to {}",
+                    story_ids, paths, chunk_start, chunk_end
+                );
+                total_story_ids += story_ids;
+                total_paths += paths;
+            }
+            chunk_start = chunk_end + 1;
+        }
+        info!(
+            "Thread backfill complete: {} story ids and {} paths updated",
+            total_story_ids, total_paths
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+-- Mirrors of the HN API's item and user objects. `IF NOT EXISTS` so databases
+-- created by hand before migrations were embedded adopt this one as a no-op.
+CREATE TABLE IF NOT EXISTS items (
+    id BIGINT PRIMARY KEY,
+    deleted BOOLEAN,
+    type TEXT,
+    by TEXT,
+    time BIGINT,
+    text TEXT,
+    dead BOOLEAN,
+    parent BIGINT,
+    poll BIGINT,
+    url TEXT,
+    score BIGINT,
+    title TEXT,
+    parts TEXT,
+    descendants BIGINT
+);
+
+-- An item's `kids` array, one row per child in display order
+CREATE TABLE IF NOT EXISTS kids (
+    item BIGINT NOT NULL REFERENCES items (id),
+    kid BIGINT NOT NULL,
+    display_order BIGINT,
+    PRIMARY KEY (item, kid)
+);
+-- Reverse lookup from a child to the item listing it
+CREATE INDEX IF NOT EXISTS kids_kid_idx ON kids (kid);
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    created BIGINT,
+    karma BIGINT,
+    about TEXT,
+    submitted TEXT
+);
@@ -0,0 +1,6 @@
+-- Item ids the realtime pipeline received but could not process before shutting down.
+-- Drained and re-queued on the next start.
+CREATE TABLE pending_updates (
+    id BIGINT PRIMARY KEY,
+    queued_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS items_parent_idx;
+DROP INDEX items_story_id_idx;
+ALTER TABLE items DROP COLUMN depth;
+ALTER TABLE items DROP COLUMN story_id;
@@ -0,0 +1,8 @@
+-- Root story and distance from it, materialized so threads don't need a recursive walk up `parent`.
+-- NULL until every ancestor of the item has been ingested.
+ALTER TABLE items ADD COLUMN story_id BIGINT;
+ALTER TABLE items ADD COLUMN depth INTEGER;
+
+CREATE INDEX items_story_id_idx ON items (story_id);
+-- Needed to walk down to children when a late parent resolves them
+CREATE INDEX IF NOT EXISTS items_parent_idx ON items (parent);
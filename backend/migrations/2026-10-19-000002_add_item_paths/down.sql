@@ -0,0 +1,2 @@
+DROP INDEX items_path_idx;
+ALTER TABLE items DROP COLUMN path;
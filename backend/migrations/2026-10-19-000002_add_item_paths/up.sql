@@ -0,0 +1,7 @@
+-- Materialized thread path: the root story id, then each ancestor's zero-padded display_order.
+-- `WHERE path <@ $root ORDER BY path` returns a subtree depth-first in display order.
+CREATE EXTENSION IF NOT EXISTS ltree;
+
+ALTER TABLE items ADD COLUMN path ltree;
+
+CREATE INDEX items_path_idx ON items USING GIST (path);
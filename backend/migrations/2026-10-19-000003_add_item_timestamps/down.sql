@@ -0,0 +1,4 @@
+DROP INDEX items_by_created_at_idx;
+DROP INDEX items_type_created_at_idx;
+DROP INDEX items_created_at_idx;
+ALTER TABLE items DROP COLUMN created_at;
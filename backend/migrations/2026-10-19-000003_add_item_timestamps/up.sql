@@ -0,0 +1,10 @@
+-- `time` is unix seconds straight from HN; this is the same instant as a real timestamp
+ALTER TABLE items ADD COLUMN created_at TIMESTAMPTZ GENERATED ALWAYS AS (to_timestamp(time)) STORED;
+
+-- "Items this week", and stories/comments in a time range
+CREATE INDEX items_created_at_idx ON items (created_at);
+CREATE INDEX items_type_created_at_idx ON items (type, created_at);
+-- A user's submissions, newest first
+CREATE INDEX items_by_created_at_idx ON items (by, created_at);
+-- Replies to an item. Already created alongside `story_id` on most databases
+CREATE INDEX IF NOT EXISTS items_parent_idx ON items (parent);
@@ -0,0 +1,3 @@
+DROP INDEX items_search_vector_idx;
+ALTER TABLE items DROP COLUMN search_vector;
+DROP FUNCTION hn_html_to_plain(TEXT);
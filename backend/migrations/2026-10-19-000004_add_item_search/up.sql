@@ -0,0 +1,16 @@
+-- HN `text` is HTML: drop tags and decode the entities HN emits, so they don't index as words
+CREATE FUNCTION hn_html_to_plain(html TEXT) RETURNS TEXT
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE RETURNS NULL ON NULL INPUT
+AS $$
+    SELECT replace(replace(replace(replace(replace(replace(
+        regexp_replace(html, '<[^>]*>', ' ', 'g'),
+        '&#x27;', ''''), '&#x2F;', '/'), '&quot;', '"'), '&gt;', '>'), '&lt;', '<'), '&amp;', '&')
+$$;
+
+-- Titles rank above body text
+ALTER TABLE items ADD COLUMN search_vector TSVECTOR GENERATED ALWAYS AS (
+    setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+    setweight(to_tsvector('english', coalesce(hn_html_to_plain(text), '')), 'B')
+) STORED;
+
+CREATE INDEX items_search_vector_idx ON items USING GIN (search_vector);
@@ -0,0 +1,71 @@
+ALTER TABLE items RENAME TO items_partitioned;
+ALTER TABLE items_partitioned RENAME CONSTRAINT items_pkey TO items_partitioned_pkey;
+ALTER TABLE kids RENAME TO kids_partitioned;
+ALTER TABLE kids_partitioned RENAME CONSTRAINT kids_pkey TO kids_partitioned_pkey;
+
+CREATE TABLE items (
+    id BIGINT PRIMARY KEY,
+    deleted BOOLEAN,
+    type TEXT,
+    by TEXT,
+    time BIGINT,
+    text TEXT,
+    dead BOOLEAN,
+    parent BIGINT,
+    poll BIGINT,
+    url TEXT,
+    score BIGINT,
+    title TEXT,
+    parts TEXT,
+    descendants BIGINT,
+    story_id BIGINT,
+    depth INTEGER,
+    path ltree,
+    created_at TIMESTAMPTZ GENERATED ALWAYS AS (to_timestamp(time)) STORED,
+    search_vector TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+        setweight(to_tsvector('english', coalesce(hn_html_to_plain(text), '')), 'B')
+    ) STORED
+);
+
+CREATE TABLE kids (
+    item BIGINT NOT NULL,
+    kid BIGINT NOT NULL,
+    display_order BIGINT,
+    PRIMARY KEY (item, kid)
+);
+
+INSERT INTO items (
+    id, deleted, type, by, time, text, dead, parent, poll, url, score, title, parts, descendants,
+    story_id, depth, path
+)
+SELECT id, deleted, type, by, time, text, dead, parent, poll, url, score, title, parts, descendants,
+       story_id, depth, path
+FROM items_partitioned;
+
+INSERT INTO kids (item, kid, display_order)
+SELECT item, kid, display_order FROM kids_partitioned;
+
+-- `embeddings` only exists once `--embed` has run
+ALTER TABLE IF EXISTS embeddings DROP CONSTRAINT IF EXISTS embeddings_story_fkey;
+DROP TABLE kids_partitioned;
+DROP TABLE items_partitioned;
+DROP FUNCTION create_item_partitions(BIGINT);
+
+ALTER TABLE kids ADD CONSTRAINT kids_item_fkey FOREIGN KEY (item) REFERENCES items (id);
+DO $$
+BEGIN
+    IF to_regclass('embeddings') IS NOT NULL THEN
+        ALTER TABLE embeddings ADD CONSTRAINT embeddings_story_fkey
+            FOREIGN KEY (story) REFERENCES items (id) ON DELETE CASCADE;
+    END IF;
+END
+$$;
+CREATE INDEX items_parent_idx ON items (parent);
+CREATE INDEX items_story_id_idx ON items (story_id);
+CREATE INDEX items_path_idx ON items USING GIST (path);
+CREATE INDEX items_created_at_idx ON items (created_at);
+CREATE INDEX items_type_created_at_idx ON items (type, created_at);
+CREATE INDEX items_by_created_at_idx ON items (by, created_at);
+CREATE INDEX items_search_vector_idx ON items USING GIN (search_vector);
+CREATE INDEX kids_kid_idx ON kids (kid);
@@ -0,0 +1,105 @@
+-- `items` and `kids` become range-partitioned by item id, in blocks of a million ids, so vacuum,
+-- analyze and reindex can work one partition at a time.
+
+-- Creates every items/kids partition needed to hold ids up to `upto`, returning the new tables.
+-- Called by the sync service as `maxitem` advances. Partition n holds ids [n * 1M, (n + 1) * 1M).
+CREATE FUNCTION create_item_partitions(upto BIGINT) RETURNS SETOF TEXT
+LANGUAGE plpgsql
+AS $$
+DECLARE
+    size CONSTANT BIGINT := 1000000;
+    n BIGINT;
+    tbl TEXT;
+BEGIN
+    -- Serializes concurrent callers, so they don't race to create the same partition
+    PERFORM pg_advisory_xact_lock(hashtext('create_item_partitions'));
+    FOR n IN 0 .. upto / size LOOP
+        FOREACH tbl IN ARRAY ARRAY['items', 'kids'] LOOP
+            IF to_regclass(format('%s_p%s', tbl, lpad(n::text, 4, '0'))) IS NULL THEN
+                EXECUTE format(
+                    'CREATE TABLE %I PARTITION OF %I FOR VALUES FROM (%s) TO (%s)',
+                    format('%s_p%s', tbl, lpad(n::text, 4, '0')), tbl, n * size, (n + 1) * size
+                );
+                RETURN NEXT format('%s_p%s', tbl, lpad(n::text, 4, '0'));
+            END IF;
+        END LOOP;
+    END LOOP;
+END
+$$;
+
+ALTER TABLE items RENAME TO items_unpartitioned;
+ALTER TABLE items_unpartitioned RENAME CONSTRAINT items_pkey TO items_unpartitioned_pkey;
+ALTER TABLE kids RENAME TO kids_unpartitioned;
+ALTER TABLE kids_unpartitioned RENAME CONSTRAINT kids_pkey TO kids_unpartitioned_pkey;
+
+CREATE TABLE items (
+    id BIGINT NOT NULL,
+    deleted BOOLEAN,
+    type TEXT,
+    by TEXT,
+    time BIGINT,
+    text TEXT,
+    dead BOOLEAN,
+    parent BIGINT,
+    poll BIGINT,
+    url TEXT,
+    score BIGINT,
+    title TEXT,
+    parts TEXT,
+    descendants BIGINT,
+    story_id BIGINT,
+    depth INTEGER,
+    path ltree,
+    created_at TIMESTAMPTZ GENERATED ALWAYS AS (to_timestamp(time)) STORED,
+    search_vector TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+        setweight(to_tsvector('english', coalesce(hn_html_to_plain(text), '')), 'B')
+    ) STORED,
+    PRIMARY KEY (id)
+) PARTITION BY RANGE (id);
+
+CREATE TABLE kids (
+    item BIGINT NOT NULL,
+    kid BIGINT NOT NULL,
+    display_order BIGINT,
+    PRIMARY KEY (item, kid)
+) PARTITION BY RANGE (item);
+
+SELECT count(*) FROM create_item_partitions(
+    (SELECT coalesce(max(id), 0) FROM items_unpartitioned) + 1000000
+);
+
+INSERT INTO items (
+    id, deleted, type, by, time, text, dead, parent, poll, url, score, title, parts, descendants,
+    story_id, depth, path
+)
+SELECT id, deleted, type, by, time, text, dead, parent, poll, url, score, title, parts, descendants,
+       story_id, depth, path
+FROM items_unpartitioned;
+
+INSERT INTO kids (item, kid, display_order)
+SELECT item, kid, display_order FROM kids_unpartitioned;
+
+-- `embeddings` only exists once `--embed` has run
+ALTER TABLE IF EXISTS embeddings DROP CONSTRAINT IF EXISTS embeddings_story_fkey;
+DROP TABLE kids_unpartitioned;
+DROP TABLE items_unpartitioned;
+
+-- Built after the copy, which is much faster than maintaining them row by row
+ALTER TABLE kids ADD CONSTRAINT kids_item_fkey FOREIGN KEY (item) REFERENCES items (id);
+DO $$
+BEGIN
+    IF to_regclass('embeddings') IS NOT NULL THEN
+        ALTER TABLE embeddings ADD CONSTRAINT embeddings_story_fkey
+            FOREIGN KEY (story) REFERENCES items (id) ON DELETE CASCADE;
+    END IF;
+END
+$$;
+CREATE INDEX items_parent_idx ON items (parent);
+CREATE INDEX items_story_id_idx ON items (story_id);
+CREATE INDEX items_path_idx ON items USING GIST (path);
+CREATE INDEX items_created_at_idx ON items (created_at);
+CREATE INDEX items_type_created_at_idx ON items (type, created_at);
+CREATE INDEX items_by_created_at_idx ON items (by, created_at);
+CREATE INDEX items_search_vector_idx ON items USING GIN (search_vector);
+CREATE INDEX kids_kid_idx ON kids (kid);
@@ -0,0 +1,2 @@
+DROP TABLE changed_users;
+DROP TABLE changed_items;
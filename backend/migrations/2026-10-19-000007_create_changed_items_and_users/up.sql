@@ -0,0 +1,13 @@
+-- Items and users the sync pipeline inserted or changed, by UTC day of the write.
+-- Read by `backend delta` to publish each day's changes.
+CREATE TABLE changed_items (
+    day DATE NOT NULL,
+    id BIGINT NOT NULL,
+    PRIMARY KEY (day, id)
+);
+
+CREATE TABLE changed_users (
+    day DATE NOT NULL,
+    id TEXT NOT NULL,
+    PRIMARY KEY (day, id)
+);
@@ -0,0 +1 @@
+DROP TABLE empty_items;
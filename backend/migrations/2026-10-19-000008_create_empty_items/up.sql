@@ -0,0 +1,5 @@
+-- Item ids HN answers with `null`, e.g. items removed before the API existed.
+-- `backend backfill` skips them, so a re-run doesn't fetch them again.
+CREATE TABLE empty_items (
+    id BIGINT PRIMARY KEY
+);
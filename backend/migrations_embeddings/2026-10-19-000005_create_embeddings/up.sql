@@ -0,0 +1,13 @@
+CREATE EXTENSION IF NOT EXISTS vector;
+
+-- e5-small-v2 embeddings of a story's discussion, one row per chunk of it
+CREATE TABLE embeddings (
+    story BIGINT NOT NULL REFERENCES items (id) ON DELETE CASCADE,
+    chunk_index INTEGER NOT NULL,
+    embedding vector(384) NOT NULL,
+    embedded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (story, chunk_index)
+);
+
+-- Embeddings are L2-normalized, so cosine distance ranks the same as inner product
+CREATE INDEX embeddings_embedding_idx ON embeddings USING hnsw (embedding vector_cosine_ops);
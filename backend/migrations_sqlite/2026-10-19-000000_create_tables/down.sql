@@ -0,0 +1,4 @@
+DROP TABLE pending_updates;
+DROP TABLE users;
+DROP TABLE kids;
+DROP TABLE items;
@@ -0,0 +1,55 @@
+-- The Postgres schema for a single-file mirror. `path` is plain text with the same
+-- dot-separated labels as the ltree column, and `time` stands in for `created_at`.
+-- `IF NOT EXISTS` so files made by hn-to-sqlite before this mirror existed adopt the
+-- base tables as they are, and only gain the columns and indexes below.
+CREATE TABLE IF NOT EXISTS items (
+    id BIGINT PRIMARY KEY,
+    deleted BOOLEAN,
+    type TEXT,
+    by TEXT,
+    time BIGINT,
+    text TEXT,
+    dead BOOLEAN,
+    parent BIGINT,
+    poll BIGINT,
+    url TEXT,
+    score BIGINT,
+    title TEXT,
+    parts TEXT,
+    descendants BIGINT
+);
+
+-- An item's `kids` array, one row per child in display order
+CREATE TABLE IF NOT EXISTS kids (
+    item BIGINT NOT NULL REFERENCES items (id),
+    kid BIGINT NOT NULL,
+    display_order BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    created BIGINT,
+    karma BIGINT,
+    about TEXT,
+    submitted TEXT
+);
+
+ALTER TABLE items ADD COLUMN story_id BIGINT;
+ALTER TABLE items ADD COLUMN depth INTEGER;
+ALTER TABLE items ADD COLUMN path TEXT;
+
+CREATE INDEX IF NOT EXISTS items_parent_idx ON items (parent);
+CREATE INDEX IF NOT EXISTS items_time_idx ON items (time);
+CREATE INDEX IF NOT EXISTS items_type_time_idx ON items (type, time);
+CREATE INDEX IF NOT EXISTS items_by_time_idx ON items (by, time);
+CREATE INDEX IF NOT EXISTS items_path_idx ON items (path);
+
+-- Upserts conflict on (item, kid), which some legacy files never made unique
+DELETE FROM kids WHERE rowid NOT IN (SELECT min(rowid) FROM kids GROUP BY item, kid);
+CREATE UNIQUE INDEX IF NOT EXISTS kids_item_kid_idx ON kids (item, kid);
+CREATE INDEX IF NOT EXISTS kids_kid_idx ON kids (kid);
+
+CREATE TABLE IF NOT EXISTS pending_updates (
+    id BIGINT PRIMARY KEY,
+    queued_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,12 @@
+-- Same as the Postgres tables. Days are stored as YYYY-MM-DD text
+CREATE TABLE changed_items (
+    day TEXT NOT NULL,
+    id BIGINT NOT NULL,
+    PRIMARY KEY (day, id)
+);
+
+CREATE TABLE changed_users (
+    day TEXT NOT NULL,
+    id TEXT NOT NULL,
+    PRIMARY KEY (day, id)
+);
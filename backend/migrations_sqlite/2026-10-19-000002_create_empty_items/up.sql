@@ -0,0 +1,4 @@
+-- Same as the Postgres table
+CREATE TABLE empty_items (
+    id BIGINT PRIMARY KEY
+);
@@ -1,13 +1,42 @@
-use axum::{routing::get, Router};
-use backend_lib::{config::Config, firebase_listener::FirebaseListener, sync_service::SyncService};
-use flume;
-use std::time::Instant;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use backend_lib::{
+    config::Config,
+    db::{
+        migrations,
+        partitions::{self, Maintenance},
+        pool::{self, PoolStats},
+        search::{SearchHit, SearchQuery},
+    },
+    export::{self, snapshot::SnapshotFormat, ExportFilter},
+    firebase_listener::FirebaseListener,
+    hn_processor::{
+        embedder::E5Embedder,
+        queue::{
+            EmbedQueue, DEFAULT_MAX_BATCH_DELAY, DEFAULT_MAX_BATCH_SIZE,
+            DEFAULT_MAX_CONCURRENT_REQUESTS,
+        },
+        HnProcessor,
+    },
+    leader::{LeaderElection, SYNC_LOCK_KEY},
+    repository::ItemRepository,
+    storage::{self, PgStorage, SqliteStorage, Storage, StorageError},
+    sync_service::{SyncService, WorkerHealth},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use diesel_async::pooled_connection::deadpool::Pool;
-use diesel_async::pooled_connection::AsyncDieselConnectionManager;
+use diesel_async::AsyncPgConnection;
 use dotenv::dotenv;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use tokio::signal::unix::{signal, SignalKind};
 use tokio_util::sync::CancellationToken;
 
@@ -22,6 +51,18 @@ struct Cli {
     /// Listen for HN updates and persist them to DB
     realtime: bool,
 
+    #[clap(long)]
+    /// Disable re-fetching young stories on the refresh schedule
+    no_refresh: bool,
+
+    #[clap(long)]
+    /// Don't write to the DB: print a JSON diff of each fetched item against it to stdout instead
+    dry_run: bool,
+
+    #[clap(long)]
+    /// Embed stories into the `embeddings` table as they're written. Needs Triton
+    embed: bool,
+
     #[clap(long)]
     /// Start catch-up from this ID
     catchup_start: Option<i64>,
@@ -29,37 +70,314 @@ struct Cli {
     #[clap(long)]
     /// Max number of records to catch up
     catchup_amt: Option<i64>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Download every item missing from the DB in an id range, then exit
+    Backfill {
+        #[clap(long)]
+        /// First ID to backfill. Defaults to 1
+        start: Option<i64>,
+
+        #[clap(long)]
+        /// Last ID to backfill. Defaults to the current max item on HN
+        end: Option<i64>,
+
+        #[clap(long, default_value_t = 100_000)]
+        /// Number of IDs to check against the DB at a time
+        chunk_size: i64,
+
+        #[clap(long, conflicts_with_all = ["start", "end"])]
+        /// Only backfill the ids of this partition
+        partition: Option<i64>,
+    },
+    /// Fill in `story_id`, `depth` and `path` for rows ingested before they were maintained, then exit
+    BackfillThreads {
+        #[clap(long, default_value_t = 10_000)]
+        /// Number of IDs to resolve at a time
+        chunk_size: i64,
+
+        #[clap(long)]
+        /// Only resolve the ids of this partition
+        partition: Option<i64>,
+    },
+    /// Compare a random sample of DB rows with the live HN API and print drift stats as JSON
+    Audit {
+        #[clap(long, default_value_t = 2_000)]
+        /// Number of IDs to sample
+        sample_size: usize,
+
+        #[clap(long, default_value_t = 20)]
+        /// Number of equal id ranges to sample evenly from
+        strata: usize,
+
+        #[clap(long)]
+        /// Only sample the ids of this partition
+        partition: Option<i64>,
+    },
+    /// List or maintain the id-range partitions of `items` and `kids`
+    Partitions {
+        #[clap(subcommand)]
+        action: PartitionAction,
+    },
+    /// Write the DB out as files for analysis elsewhere, then exit
+    Export {
+        #[clap(subcommand)]
+        format: ExportFormat,
+    },
+    /// Write a dated, zstd-compressed dump of the whole DB with a manifest of row counts and checksums, then exit
+    Snapshot {
+        #[clap(long)]
+        /// Directory to write into. Created if missing
+        out: PathBuf,
+
+        #[clap(long, default_value_t = SnapshotFormat::Sqlite)]
+        /// `sqlite` for one database file, or `jsonl` for a file per table
+        format: SnapshotFormat,
+
+        #[clap(long)]
+        /// Date in the file names, e.g. 2024-01-31. Defaults to today (UTC)
+        date: Option<NaiveDate>,
+
+        #[clap(long, default_value_t = 9)]
+        /// Zstd compression level, 1 to 22
+        zstd_level: i32,
+    },
+    /// Write the items and users changed on one day as zstd JSONL, and add them to the rolling `deltas.json`, then exit
+    Delta {
+        #[clap(long)]
+        /// Directory holding `deltas.json`. Created if missing
+        out: PathBuf,
+
+        #[clap(long)]
+        /// Day of changes to write, e.g. 2024-01-31. Defaults to yesterday (UTC)
+        date: Option<NaiveDate>,
+
+        #[clap(long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..))]
+        /// Number of days, up to `date`, that `deltas.json` keeps. Older files are deleted
+        retain_days: u32,
+
+        #[clap(long, default_value_t = 9)]
+        /// Zstd compression level, 1 to 22
+        zstd_level: i32,
+    },
+    /// Bring this DB up to date with the days listed in a directory written by `delta`, then exit
+    ApplyDelta {
+        /// Directory holding `deltas.json`
+        dir: PathBuf,
+
+        #[clap(long)]
+        /// Skip the days before this one, e.g. those already applied
+        since: Option<NaiveDate>,
+    },
+    /// Inspect or roll back the DB schema. Pending migrations otherwise run on every start
+    Migrate {
+        #[clap(subcommand)]
+        action: MigrateAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PartitionAction {
+    /// Print every partition with its row estimate and size as JSON
+    List,
+    /// Create partitions up to this id now, rather than when ingestion reaches it
+    Create { max_id: i64 },
+    /// VACUUM (ANALYZE) one partition
+    Vacuum { partition: i64 },
+    /// ANALYZE one partition
+    Analyze { partition: i64 },
+    /// Rebuild the indexes of one partition without blocking writes
+    Reindex { partition: i64 },
+}
+
+#[derive(Subcommand, Debug)]
+enum ExportFormat {
+    /// Zstd-compressed Parquet files of `items`, `kids` and `users`, split by partition
+    Parquet {
+        #[clap(long)]
+        /// Directory to write into. Created if missing
+        out: PathBuf,
+
+        #[clap(long)]
+        /// First item ID to export
+        start: Option<i64>,
+
+        #[clap(long)]
+        /// Last item ID to export
+        end: Option<i64>,
+
+        #[clap(long)]
+        /// Only export items and users created at or after this time, e.g. 2024-01-01T00:00:00Z
+        after: Option<DateTime<Utc>>,
+
+        #[clap(long)]
+        /// Only export items and users created before this time
+        before: Option<DateTime<Utc>>,
+
+        #[clap(long, default_value_t = 3)]
+        /// Zstd compression level, 1 to 22
+        zstd_level: i32,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MigrateAction {
+    /// List every migration and whether it has been applied
+    Status,
+    /// Apply pending migrations
+    Run,
+    /// Roll back the most recently applied migrations
+    Revert {
+        #[clap(long, default_value_t = 1)]
+        /// Number of migrations to roll back
+        steps: usize,
+    },
+}
+
+#[derive(Clone)]
+struct AppState {
+    health: Arc<WorkerHealth>,
+    /// `None` on a SQLite mirror
+    pool: Option<Pool<AsyncPgConnection>>,
+    items: ItemRepository,
 }
 
 // Health endpoint handler
-async fn health_handler() -> String {
-    "Healthy".to_string()
+async fn health_handler(State(state): State<AppState>) -> (StatusCode, String) {
+    let health = &state.health;
+    if health.is_healthy() {
+        (StatusCode::OK, "Healthy".to_string())
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!(
+                "Unhealthy: update workers keep failing ({} restarts)",
+                health.restarts()
+            ),
+        )
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    info!("Starting embedding backend");
-    dotenv().ok();
+/// Saturation of the Postgres pool
+async fn db_health_handler(
+    State(state): State<AppState>,
+) -> Result<Json<PoolStats>, (StatusCode, String)> {
+    not_found(state.pool.as_ref().map(pool::stats))
+}
 
-    let config = Config::from_env().expect("Config incorrectly specified");
-    env_logger::init();
-    let args = Cli::parse();
-    debug!("Config loaded");
+/// Logs a failed query and hides its details from the client
+fn internal_error(err: StorageError) -> (StatusCode, String) {
+    error!("Request failed: {}", err);
+    match err {
+        StorageError::DBPoolError(_) | StorageError::PoolExhausted { .. } => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Database unavailable".to_string(),
+        ),
+        StorageError::Unsupported(_) => (StatusCode::NOT_IMPLEMENTED, err.to_string()),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed".to_string(),
+        ),
+    }
+}
 
-    let pool_config =
-        AsyncDieselConnectionManager::<diesel_async::AsyncPgConnection>::new(&config.db_url);
-    let pool = Pool::builder(pool_config)
-        .build()
-        .expect("Could not establish connection!");
+fn not_found<T>(found: Option<T>) -> Result<Json<T>, (StatusCode, String)> {
+    found
+        .map(Json)
+        .ok_or((StatusCode::NOT_FOUND, "Not found".to_string()))
+}
 
-    let shutdown_token = CancellationToken::new();
-    // TODO profile this constant
-    let sync_service = SyncService::new(config.hn_api_url.clone(), pool.clone(), 200);
-    if !args.no_catchup {
+/// Full-text search over titles and text, see `SearchQuery` for the parameters
+async fn search_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, (StatusCode, String)> {
+    if query.q.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "`q` must not be empty".to_string()));
+    }
+    let hits = state.items.search(&query).await.map_err(internal_error)?;
+    Ok(Json(hits))
+}
+
+/// Ingestion settings from the CLI, reapplied each time this replica becomes leader
+#[derive(Clone, Copy)]
+struct IngestOptions {
+    catchup: bool,
+    catchup_amt: Option<i64>,
+    catchup_start: Option<i64>,
+    refresh: bool,
+}
+
+/**
+Runs ingestion whenever this replica holds the leader lock, until `shutdown_token` fires.
+
+Standbys only serve HTTP. If leadership is lost, ingestion is cancelled and drained,
+and this replica goes back to waiting for the lock.
+*/
+async fn lead_ingestion(
+    election: LeaderElection,
+    sync_service: Arc<SyncService>,
+    config: Arc<Config>,
+    options: IngestOptions,
+    shutdown_token: CancellationToken,
+) {
+    loop {
+        info!("Waiting for leader lock");
+        let mut leadership = match election.acquire(&shutdown_token).await {
+            Ok(Some(leadership)) => leadership,
+            Ok(None) => return,
+            Err(err) => {
+                error!("Leader election failed: {}", err);
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => continue,
+                    _ = shutdown_token.cancelled() => return,
+                }
+            }
+        };
+
+        info!("Elected leader, starting ingestion");
+        let ingest_token = shutdown_token.child_token();
+        let abort_token = CancellationToken::new();
+        let ingestion = run_ingestion(
+            sync_service.clone(),
+            config.clone(),
+            options,
+            ingest_token.clone(),
+            abort_token.clone(),
+        );
+        tokio::pin!(ingestion);
+        tokio::select! {
+            _ = &mut ingestion => return,
+            err = leadership.lost() => {
+                error!("Lost leadership: {}. Stopping ingestion", err);
+                // Another replica may already be writing, so hand the queue over unprocessed
+                abort_token.cancel();
+                ingest_token.cancel();
+                ingestion.await;
+            }
+        }
+    }
+}
+
+/// Catches up, then follows HN updates until `cancel_token` fires. Firing `abort_token` as well
+/// skips draining the realtime queue
+async fn run_ingestion(
+    sync_service: Arc<SyncService>,
+    config: Arc<Config>,
+    options: IngestOptions,
+    cancel_token: CancellationToken,
+    abort_token: CancellationToken,
+) {
+    if options.catchup {
         let start_time = Instant::now();
         info!("Beginning catchup");
         sync_service
-            .catchup(args.catchup_amt, args.catchup_start)
+            .catchup(options.catchup_amt, options.catchup_start)
             .await
             .expect("Catchup failed");
         let elapsed_time = start_time.elapsed();
@@ -68,38 +386,420 @@ async fn main() {
         info!("Skipping catchup");
     }
 
+    let refresh_handle = if options.refresh {
+        let refresh_service = sync_service.clone();
+        let refresh_cancel_token = cancel_token.clone();
+        let schedule = config.refresh_schedule.clone();
+        Some(tokio::spawn(async move {
+            if let Err(err) = refresh_service
+                .refresh_young_stories(schedule, Duration::from_secs(60), refresh_cancel_token)
+                .await
+            {
+                error!("Story refresh has stopped: {}", err);
+            }
+        }))
+    } else {
+        info!("Skipping story refresh");
+        None
+    };
+
+    let partitions_service = sync_service.clone();
+    let partitions_cancel_token = cancel_token.clone();
+    let partitions_handle = tokio::spawn(async move {
+        if let Err(err) = partitions_service
+            .extend_partitions(Duration::from_secs(60 * 60), partitions_cancel_token)
+            .await
+        {
+            error!("Partition upkeep has stopped: {}", err);
+        }
+    });
+
     let (sender, receiver) = flume::unbounded::<i64>();
-    let listener_cancel_token = shutdown_token.clone();
+    let (profiles_sender, profiles_receiver) = flume::unbounded::<String>();
+    // Re-queue whatever the previous shutdown couldn't process
+    let pending_ids = sync_service
+        .take_pending_updates()
+        .await
+        .expect("Could not load pending updates");
+    if !pending_ids.is_empty() {
+        info!("Re-queueing {} ids from last shutdown", pending_ids.len());
+    }
+    for id in pending_ids {
+        sender.send(id).expect("Realtime queue closed unexpectedly");
+    }
+    let listener_cancel_token = cancel_token.clone();
+    let hn_api_url = config.hn_api_url.clone();
     let hn_updates_handle = tokio::spawn(async move {
-        FirebaseListener::new(config.hn_api_url.clone())
+        FirebaseListener::new(hn_api_url)
             .unwrap()
-            .listen_to_updates(sender, listener_cancel_token)
+            .listen_to_updates(sender, profiles_sender, listener_cancel_token)
             .await
             .expect("HN update producer has failed!");
     });
 
+    // Ends once the listener drops its sender
+    let profiles_service = sync_service.clone();
+    let profiles_handle = tokio::spawn(async move {
+        if let Err(err) = profiles_service.update_profiles(profiles_receiver).await {
+            error!("Profile updates have stopped: {}", err);
+        }
+    });
+
     // TODO make this number less arbitrary
     let n_update_workers = 32;
+    let orchestrator_cancel_token = cancel_token.clone();
+    let shutdown_deadline = config.shutdown_deadline;
     let update_orchestrator_handle = tokio::spawn(async move {
         sync_service
-            .realtime_update(n_update_workers, receiver)
+            .realtime_update(
+                n_update_workers,
+                receiver,
+                orchestrator_cancel_token,
+                abort_token,
+                shutdown_deadline,
+            )
             .await
             .expect("HN update consumer has failed!");
     });
 
-    /*
-    // let text: &str = "When I was a young boy, my father took me into the city to see a marching band";
-    let embedder = E5Embedder::new(&config.triton_server_addr)
-        .await
-        .expect("Cannot connect to Triton!");
+    cancel_token.cancelled().await;
+    hn_updates_handle.await.unwrap();
+    profiles_handle.await.unwrap();
+    update_orchestrator_handle.await.unwrap();
+    partitions_handle.await.unwrap();
+    if let Some(handle) = refresh_handle {
+        handle.await.unwrap();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    info!("Starting embedding backend");
+    dotenv().ok();
+
+    let config = Config::from_env().expect("Config incorrectly specified");
+    env_logger::init();
+    let args = Cli::parse();
+    debug!("Config loaded");
+
+    if let Some(Command::Migrate { action }) = args.command {
+        match action {
+            MigrateAction::Status => {
+                for migration in migrations::status(&config.db_url)
+                    .await
+                    .expect("Could not read migration status")
+                {
+                    let state = if migration.applied {
+                        "applied"
+                    } else if migration.optional {
+                        "optional"
+                    } else {
+                        "pending"
+                    };
+                    println!("{:<8} {}", state, migration.name);
+                }
+            }
+            MigrateAction::Run => {
+                let applied = migrations::run_pending(&config.db_url)
+                    .await
+                    .expect("Migrations failed");
+                info!("Applied {} migrations: {:?}", applied.len(), applied);
+            }
+            MigrateAction::Revert { steps } => {
+                let reverted = migrations::revert(&config.db_url, steps)
+                    .await
+                    .expect("Rollback failed");
+                info!("Reverted {} migrations: {:?}", reverted.len(), reverted);
+            }
+        }
+        return;
+    }
+    if args.dry_run {
+        // Leave the schema alone too, but say so if the code expects a newer one
+        let pending = migrations::status(&config.db_url)
+            .await
+            .expect("Could not read migration status")
+            .into_iter()
+            .filter(|migration| !migration.applied && !migration.optional)
+            .count();
+        if pending > 0 {
+            warn!("Dry run: {} migrations pending, queries may fail", pending);
+        }
+    } else {
+        let applied = migrations::run_pending_at_startup(&config.db_url)
+            .await
+            .unwrap_or_else(|err| panic!("Migrations failed: {}", err));
+        if !applied.is_empty() {
+            info!("Applied {} migrations: {:?}", applied.len(), applied);
+        }
+    }
 
-    debug!("Embedder initialized");
-    let embedding = embedder.encode(text).await.expect("Embedding failed!");
-    println!("{:?}", embedding); */
+    // A SQLite mirror has no pool: features that need Postgres check for one
+    let sqlite = storage::is_sqlite_url(&config.db_url);
+    let (storage, pool): (Arc<dyn Storage>, Option<Pool<AsyncPgConnection>>) = if sqlite {
+        info!("Using SQLite database {}", config.db_url);
+        let storage = SqliteStorage::open(&config.db_url).expect("Could not open SQLite database");
+        (Arc::new(storage), None)
+    } else {
+        let pool = pool::build(&config.db_url, &config.db_pool).expect("Could not build DB pool");
+        (Arc::new(PgStorage::new(pool.clone())), Some(pool))
+    };
+
+    let shutdown_token = CancellationToken::new();
+    // TODO profile this constant
+    let sync_service = SyncService::new(
+        config.hn_api_url.clone(),
+        storage.clone(),
+        200,
+        args.dry_run,
+    );
+    match args.command {
+        Some(Command::Backfill {
+            start,
+            end,
+            chunk_size,
+            partition,
+        }) => {
+            let (start, end) = match partition.map(partitions::id_range) {
+                Some(ids) => (Some(*ids.start()), Some(*ids.end())),
+                None => (start, end),
+            };
+            let start_time = Instant::now();
+            sync_service
+                .backfill(start, end, chunk_size)
+                .await
+                .expect("Backfill failed");
+            info!("Backfill time elapsed: {:?}", start_time.elapsed());
+            return;
+        }
+        Some(Command::BackfillThreads {
+            chunk_size,
+            partition,
+        }) => {
+            let start_time = Instant::now();
+            sync_service
+                .backfill_threads(chunk_size, partition.map(partitions::id_range))
+                .await
+                .expect("Thread backfill failed");
+            info!("Thread backfill time elapsed: {:?}", start_time.elapsed());
+            return;
+        }
+        Some(Command::Audit {
+            sample_size,
+            strata,
+            partition,
+        }) => {
+            let report = sync_service
+                .audit(sample_size, strata, partition.map(partitions::id_range))
+                .await
+                .expect("Audit failed");
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&report).expect("Could not serialize audit report")
+            );
+            return;
+        }
+        Some(Command::Partitions { action }) => {
+            let pool = pool.expect("Partitions need a Postgres DB_URL");
+            let mut conn = pool.get().await.expect("Could not connect to DB");
+            let start_time = Instant::now();
+            let (partition, maintenance) = match action {
+                PartitionAction::List => {
+                    let partitions = partitions::list(&mut conn)
+                        .await
+                        .expect("Could not list partitions");
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&partitions)
+                            .expect("Could not serialize partitions")
+                    );
+                    return;
+                }
+                PartitionAction::Create { max_id } => {
+                    let created = partitions::ensure_partitions(&mut conn, max_id)
+                        .await
+                        .expect("Could not create partitions");
+                    info!("Created partitions {:?}", created);
+                    return;
+                }
+                PartitionAction::Vacuum { partition } => (partition, Maintenance::Vacuum),
+                PartitionAction::Analyze { partition } => (partition, Maintenance::Analyze),
+                PartitionAction::Reindex { partition } => (partition, Maintenance::Reindex),
+            };
+            partitions::maintain(&mut conn, partition, maintenance)
+                .await
+                .expect("Partition maintenance failed");
+            info!(
+                "{:?} of partition {} time elapsed: {:?}",
+                maintenance,
+                partition,
+                start_time.elapsed()
+            );
+            return;
+        }
+        Some(Command::Export {
+            format:
+                ExportFormat::Parquet {
+                    out,
+                    start,
+                    end,
+                    after,
+                    before,
+                    zstd_level,
+                },
+        }) => {
+            let filter = ExportFilter {
+                start,
+                end,
+                after,
+                before,
+            };
+            let start_time = Instant::now();
+            let summary = export::parquet::export(storage.as_ref(), &out, &filter, zstd_level)
+                .await
+                .expect("Parquet export failed");
+            info!("Parquet export time elapsed: {:?}", start_time.elapsed());
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("Could not serialize export summary")
+            );
+            return;
+        }
+        Some(Command::Snapshot {
+            out,
+            format,
+            date,
+            zstd_level,
+        }) => {
+            let date = date.unwrap_or_else(|| Utc::now().date_naive());
+            let start_time = Instant::now();
+            let manifest =
+                export::snapshot::snapshot(storage.as_ref(), &out, format, date, zstd_level)
+                    .await
+                    .expect("Snapshot failed");
+            info!("Snapshot time elapsed: {:?}", start_time.elapsed());
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&manifest).expect("Could not serialize manifest")
+            );
+            return;
+        }
+        Some(Command::Delta {
+            out,
+            date,
+            retain_days,
+            zstd_level,
+        }) => {
+            let date = date.unwrap_or_else(|| Utc::now().date_naive() - chrono::Duration::days(1));
+            let manifest =
+                export::delta::write(storage.as_ref(), &out, date, retain_days, zstd_level)
+                    .await
+                    .expect("Delta export failed");
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&manifest).expect("Could not serialize manifest")
+            );
+            return;
+        }
+        Some(Command::ApplyDelta { dir, since }) => {
+            let start_time = Instant::now();
+            let summary = export::delta::apply(storage.as_ref(), &dir, since)
+                .await
+                .expect("Applying deltas failed");
+            info!("Apply delta time elapsed: {:?}", start_time.elapsed());
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("Could not serialize summary")
+            );
+            return;
+        }
+        Some(Command::Migrate { .. }) => unreachable!("handled before connecting the pool"),
+        None => {}
+    }
+
+    let sync_service = Arc::new(sync_service);
+    let worker_health = sync_service.health();
+    let ingest_options = IngestOptions {
+        catchup: !args.no_catchup,
+        catchup_amt: args.catchup_amt,
+        catchup_start: args.catchup_start,
+        refresh: !args.no_refresh,
+    };
+    let config = Arc::new(config);
+    let db_pool = pool.clone();
+    let processor_handle = if args.embed {
+        let pool = pool.expect("Embeddings need a Postgres DB_URL");
+        if !args.dry_run {
+            let applied = migrations::run_embedding_migrations(&config.db_url)
+                .await
+                .expect("Embedding migrations failed, is pgvector installed?");
+            if !applied.is_empty() {
+                info!(
+                    "Applied {} embedding migrations: {:?}",
+                    applied.len(),
+                    applied
+                );
+            }
+        }
+        let embedder = E5Embedder::new(&config.triton_server_addr)
+            .await
+            .expect("Cannot connect to Triton!");
+        debug!("Embedder initialized");
+        let queue = EmbedQueue::new(
+            Arc::new(embedder),
+            DEFAULT_MAX_BATCH_SIZE,
+            DEFAULT_MAX_BATCH_DELAY,
+            DEFAULT_MAX_CONCURRENT_REQUESTS,
+        );
+        let processor = HnProcessor::new(
+            queue,
+            ItemRepository::new(storage.clone()),
+            pool,
+            sync_service.events(),
+        );
+        Some(tokio::spawn(async move { processor.start().await }))
+    } else {
+        None
+    };
+    let election = LeaderElection::new(config.db_url.clone(), SYNC_LOCK_KEY);
+    let ingest_cancel_token = shutdown_token.clone();
+    let dry_run = args.dry_run;
+    let mut ingest_handle = tokio::spawn(async move {
+        if dry_run || sqlite {
+            // Nothing is written, or the file has a single writer anyway, so there's no need to wait for leadership
+            if dry_run {
+                info!("Dry run: diffing against the DB without writing");
+            }
+            run_ingestion(
+                sync_service,
+                config,
+                ingest_options,
+                ingest_cancel_token,
+                CancellationToken::new(),
+            )
+            .await
+        } else {
+            lead_ingestion(
+                election,
+                sync_service,
+                config,
+                ingest_options,
+                ingest_cancel_token,
+            )
+            .await
+        }
+    });
 
     let app = Router::new()
         .route("/", get(|| async { "Hello, world!" }))
-        .route("/health", get(health_handler));
+        .route("/health", get(health_handler))
+        .route("/health/db", get(db_health_handler))
+        .route("/search", get(search_handler))
+        .with_state(AppState {
+            health: worker_health,
+            pool: db_pool,
+            items: ItemRepository::new(storage),
+        });
     let server_handle = tokio::spawn(async move {
         axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
             .serve(app.into_make_service())
@@ -117,12 +817,19 @@ async fn main() {
         _ = sigint.recv() => {
             info!("SIGINT received, shutting down.");
         }
+        result = &mut ingest_handle => {
+            // Only ends early on a failure; exit so the orchestrator restarts us
+            result.unwrap();
+            panic!("Ingestion stopped unexpectedly!");
+        }
     }
 
-    // Trigger the shutdown
+    // Trigger the shutdown: the listener stops taking updates, and the workers drain what's left
     shutdown_token.cancel();
     // Wait for all tasks to complete
-    hn_updates_handle.await.unwrap();
-    update_orchestrator_handle.await.unwrap();
+    ingest_handle.await.unwrap();
+    if let Some(handle) = processor_handle {
+        handle.abort();
+    }
     server_handle.abort();
 }
@@ -30,6 +30,8 @@ job "backend" {
 
     task "backend-task" {
       driver = "docker"
+      # Longer than SHUTDOWN_DEADLINE, so the realtime queue can drain before SIGKILL
+      kill_timeout = "30s"
 
       config {
         image = "ghcr.io/endlessreform/backend:canary"